@@ -1,11 +1,659 @@
+#![allow(clippy::too_many_arguments)]
+
 use scrypto::prelude::*;
+use std::cmp::Ordering;
+
+/// Parked collateral can always be swept once it has been unclaimed for this long (30 days).
+pub const MIN_COLLATERAL_GRACE_PERIOD: i64 = 2_592_000;
+
+/// Hard cap on the spread a peg controller can set, in either direction.
+pub const MAX_PEG_SPREAD: Decimal = dec!("0.05");
+
+/// Default bounds for usd_per_fusd, also the bounds for the initial token's rate.
+pub const DEFAULT_USD_PER_FUSD_MIN: Decimal = dec!("0.95");
+pub const DEFAULT_USD_PER_FUSD_MAX: Decimal = dec!("1.05");
+
+/// Window over which direct usd_per_fusd tuning is limited (1 day).
+pub const USD_PER_FUSD_TUNE_WINDOW: i64 = 86_400;
+
+/// Stored version of a PSM token, so new per-token fields can be added without breaking existing entries.
+/// Entries are upgraded to the latest version the first time they are accessed through `UsdTokenStore`.
+#[derive(ScryptoSbor)]
+#[allow(clippy::large_enum_variant)]
+pub enum UsdTokenState {
+    V1(UsdTokenV1),
+    V2(UsdToken),
+}
+
+impl UsdTokenState {
+    pub fn is_latest(&self) -> bool {
+        matches!(self, UsdTokenState::V2(_))
+    }
+
+    // takes the old version by value, so its vault moves into the new version
+    pub fn upgrade(self) -> Self {
+        match self {
+            UsdTokenState::V1(usd_token) => UsdTokenState::V2(usd_token.upgrade()),
+            latest => latest,
+        }
+    }
+
+    pub fn latest(&self) -> &UsdToken {
+        match self {
+            UsdTokenState::V2(usd_token) => usd_token,
+            _ => panic!("Token entry not upgraded"),
+        }
+    }
+
+    pub fn latest_mut(&mut self) -> &mut UsdToken {
+        match self {
+            UsdTokenState::V2(usd_token) => usd_token,
+            _ => panic!("Token entry not upgraded"),
+        }
+    }
+}
+
+/// Access to the stored PSM tokens that upgrades outdated entries on the way.
+pub trait UsdTokenStore {
+    fn upgraded(&self, token: &ResourceAddress) -> KeyValueEntryRef<'_, UsdTokenState>;
+    fn upgraded_mut(&mut self, token: &ResourceAddress) -> KeyValueEntryRefMut<'_, UsdTokenState>;
+}
+
+impl UsdTokenStore for KeyValueStore<ResourceAddress, UsdTokenState> {
+    fn upgraded(&self, token: &ResourceAddress) -> KeyValueEntryRef<'_, UsdTokenState> {
+        let is_latest = self.get(token).expect("Unknown token").is_latest();
+        if !is_latest {
+            let usd_token_state = self.remove(token).unwrap();
+            self.insert(*token, usd_token_state.upgrade());
+        }
+
+        self.get(token).unwrap()
+    }
+
+    fn upgraded_mut(&mut self, token: &ResourceAddress) -> KeyValueEntryRefMut<'_, UsdTokenState> {
+        drop(self.upgraded(token));
+        self.get_mut(token).unwrap()
+    }
+}
+
+/// PSM token as stored before rate limits, strategies, access control, FX pricing and surplus tracking.
+#[derive(ScryptoSbor)]
+pub struct UsdTokenV1 {
+    pub vault: FungibleVault,
+    pub accepted: bool,
+    pub usd_per_fusd: Decimal,
+    pub fusd_minted: Decimal,
+}
+
+impl UsdTokenV1 {
+    fn upgrade(self) -> UsdToken {
+        UsdToken {
+            vault: self.vault,
+            accepted: self.accepted,
+            usd_per_fusd: self.usd_per_fusd,
+            fusd_minted: self.fusd_minted,
+            mint_limit: None,
+            redeem_limit: None,
+            strategy: None,
+            deployed: Decimal::ZERO,
+            access: PsmAccess::Public,
+            fx_priced: false,
+            surplus: Decimal::ZERO,
+            peg_controller: None,
+        }
+    }
+}
 
+/// Latest version of a PSM token.
 #[derive(ScryptoSbor)]
 pub struct UsdToken {
     pub vault: FungibleVault,
     pub accepted: bool,
+    /// For FX priced tokens this is only the spread on top of the oracle's FX rate.
+    pub usd_per_fusd: Decimal,
+    pub fusd_minted: Decimal,
+    pub mint_limit: Option<RateLimit>,
+    pub redeem_limit: Option<RateLimit>,
+    pub strategy: Option<StrategyConfig>,
+    /// Amount of the token currently deployed in the strategy, excluding unharvested profits.
+    pub deployed: Decimal,
+    /// Who can mint and redeem against this token.
+    pub access: PsmAccess,
+    /// Whether the token is a non-USD stable, priced in USD through the oracle.
+    pub fx_priced: bool,
+    /// Part of the backing that was earned through spread fees or donated, and doesn't back minted fUSD.
+    pub surplus: Decimal,
+    /// Optional controller that derives the mint and redeem spread from the fUSD market price.
+    pub peg_controller: Option<PegController>,
+}
+
+impl UsdToken {
+    // deployed funds count toward the backing, even though only the vault can pay out directly
+    pub fn backing(&self) -> Decimal {
+        self.vault.amount() + self.deployed
+    }
+
+    // surplus is spent on payouts or turned into backing for newly minted fUSD
+    // FX priced tokens can't be used, as their value per fUSD moves with the FX rate
+    pub fn spend_surplus(&mut self, amount: Decimal) {
+        assert!(
+            !self.fx_priced,
+            "FX priced tokens can't be used for surplus payouts"
+        );
+        assert!(amount <= self.surplus, "Not enough surplus in this token");
+        self.surplus -= amount;
+    }
+
+    // whether less than 1 fUSD can still be redeemed against the token, surplus can't be redeemed
+    // FX priced tokens have no fixed value per fUSD without an oracle price, so they only count once fully drained
+    pub fn redemptions_exhausted(&self) -> bool {
+        let redeemable = self.backing() - self.surplus;
+        if self.fusd_minted < Decimal::ONE || redeemable <= Decimal::ZERO {
+            return true;
+        }
+
+        !self.fx_priced && redeemable < self.usd_per_fusd
+    }
+}
+
+/// Who can use the PSM for a token, for stables whose issuer restricts who may hold them.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub enum PsmAccess {
+    Public,
+    /// A proof of this resource has to be in the auth zone.
+    Badge(ResourceAddress),
+    /// A proof of this non-fungible has to be in the auth zone.
+    NonFungible(NonFungibleGlobalId),
+    /// The owner of one of these accounts has to authorize the transaction.
+    Accounts(Vec<ComponentAddress>),
+}
+
+impl PsmAccess {
+    pub fn assert_access(&self) {
+        let rule = match self {
+            PsmAccess::Public => return,
+            PsmAccess::Badge(resource_address) => rule!(require(*resource_address)),
+            PsmAccess::NonFungible(global_id) => rule!(require(global_id.clone())),
+            PsmAccess::Accounts(accounts) => {
+                let mut requirements: Vec<CompositeRequirement> = vec![];
+                for account_address in accounts {
+                    let account: Global<Account> = Global::from(*account_address);
+                    match account.get_owner_role().rule {
+                        AccessRule::AllowAll => return,
+                        AccessRule::DenyAll => {}
+                        AccessRule::Protected(requirement) => requirements.push(requirement),
+                    }
+                }
+                AccessRule::Protected(CompositeRequirement::AnyOf(requirements))
+            }
+        };
+
+        Runtime::assert_access_rule(rule);
+    }
+}
+
+/// A whitelisted yield strategy for a PSM token's idle reserves.
+/// The strategy component takes a bucket through `deposit_method`, returns the requested amount through
+/// `withdraw_method` and reports the balance it holds for this component through `balance_method`.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct StrategyConfig {
+    pub component: ComponentAddress,
+    pub deposit_method: String,
+    pub withdraw_method: String,
+    pub balance_method: String,
+    /// Maximum amount of the token deployed in the strategy.
+    pub allocation_cap: Decimal,
+    /// Amount that always stays in the vault, so redemptions can be paid out.
+    pub min_liquid_buffer: Decimal,
+}
+
+/// Pause flags, combined into a bitmap. Each entry point checks its own flag.
+pub const PAUSE_MINT: u8 = 1 << 0;
+pub const PAUSE_REDEEM: u8 = 1 << 1;
+pub const PAUSE_CLOSE_LOAN: u8 = 1 << 2;
+pub const PAUSE_PARTIAL_LIQUIDATION: u8 = 1 << 3;
+pub const PAUSE_RETRIEVE_COLLATERAL: u8 = 1 << 4;
+pub const PAUSE_FLASH_MINT: u8 = 1 << 5;
+pub const PAUSE_ALL: u8 = PAUSE_MINT
+    | PAUSE_REDEEM
+    | PAUSE_CLOSE_LOAN
+    | PAUSE_PARTIAL_LIQUIDATION
+    | PAUSE_RETRIEVE_COLLATERAL
+    | PAUSE_FLASH_MINT;
+/// Flags the guardian can set, the others can only be paused by the owner.
+pub const PAUSE_GUARDIAN: u8 =
+    PAUSE_MINT | PAUSE_REDEEM | PAUSE_CLOSE_LOAN | PAUSE_PARTIAL_LIQUIDATION;
+
+/// A structure for returning which entry points are currently paused.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct PauseStatus {
+    /// The raw pause bitmap.
+    pub flags: u8,
+    pub mint_with_usd: bool,
+    pub redeem_with_fusd: bool,
+    pub close_loan: bool,
+    pub partial_liquidate_cdp: bool,
+    /// Covers every way of retrieving parked or claimable collateral.
+    pub retrieve_collateral: bool,
+    pub flash_mint: bool,
+}
+
+/// Rolling-window limit, used for fUSD volume and for relative usd_per_fusd changes.
+/// Used capacity is released linearly over the window, so the limit applies to any window of that length.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct RateLimit {
+    /// Maximum amount that can be used within one window.
+    pub max_amount: Decimal,
+    /// Length of the window in seconds.
+    pub window_seconds: i64,
+    /// Amount of the capacity in use at `last_update`.
+    pub used: Decimal,
+    /// Timestamp of the last time the used capacity was updated.
+    pub last_update: Instant,
+}
+
+impl RateLimit {
+    pub fn new(max_amount: Decimal, window_seconds: i64) -> Self {
+        assert!(max_amount >= Decimal::ZERO, "Rate limit cannot be negative");
+        assert!(window_seconds > 0, "Rate limit window must be positive");
+
+        Self {
+            max_amount,
+            window_seconds,
+            used: Decimal::ZERO,
+            last_update: Clock::current_time_rounded_to_seconds(),
+        }
+    }
+
+    pub fn used_at(&self, now: Instant) -> Decimal {
+        let elapsed = now.seconds_since_unix_epoch - self.last_update.seconds_since_unix_epoch;
+        let released =
+            self.max_amount * Decimal::from(elapsed) / Decimal::from(self.window_seconds);
+
+        (self.used - released).max(Decimal::ZERO)
+    }
+
+    pub fn remaining(&self) -> Decimal {
+        let now = Clock::current_time_rounded_to_seconds();
+        (self.max_amount - self.used_at(now)).max(Decimal::ZERO)
+    }
+
+    pub fn consume(&mut self, amount: Decimal, error_message: &str) {
+        let now = Clock::current_time_rounded_to_seconds();
+        let used = self.used_at(now) + amount;
+        assert!(used <= self.max_amount, "{}", error_message);
+
+        self.used = used;
+        self.last_update = now;
+    }
+}
+
+/// Derives the PSM spread from the fUSD market price. Below peg minting gets a fee, above peg redeeming does.
+/// The price feed can be a DEX pool adapter or an oracle, as long as `price_method` takes no arguments
+/// and returns the fUSD price in USD.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct PegController {
+    pub price_feed: ComponentAddress,
+    pub price_method: String,
+    /// Spread per unit of deviation from the peg.
+    pub sensitivity: Decimal,
+    /// Maximum spread in either direction.
+    pub max_spread: Decimal,
+    /// Maximum change of the spread per `period_seconds`, released linearly.
+    pub max_change_per_period: Decimal,
+    pub period_seconds: i64,
+    /// Current spread, positive is a mint fee and negative a redeem fee.
+    pub spread: Decimal,
+    pub last_update: Instant,
+}
+
+impl PegController {
+    pub fn mint_spread(&self) -> Decimal {
+        self.spread.max(Decimal::ZERO)
+    }
+
+    pub fn redeem_spread(&self) -> Decimal {
+        (-self.spread).max(Decimal::ZERO)
+    }
+
+    pub fn update(&mut self, fusd_price: Decimal) {
+        self.update_at(fusd_price, Clock::current_time_rounded_to_seconds());
+    }
+
+    // moves the spread towards its target for the given price, at most by the change allowed since the last update
+    pub fn update_at(&mut self, fusd_price: Decimal, now: Instant) {
+        let elapsed = now.seconds_since_unix_epoch - self.last_update.seconds_since_unix_epoch;
+        let max_change = (self.max_change_per_period * Decimal::from(elapsed)
+            / Decimal::from(self.period_seconds))
+        .min(self.max_change_per_period);
+
+        let target = ((Decimal::ONE - fusd_price) * self.sensitivity)
+            .clamp(-self.max_spread, self.max_spread);
+        let change = (target - self.spread).clamp(-max_change, max_change);
+
+        self.spread += change;
+        self.last_update = now;
+    }
+}
+
+/// Privileged operations this component performs on Flux using its badges.
+#[derive(ScryptoSbor, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BadgeOperation {
+    FreeFusd,
+    CloseCdp,
+    PartialCloseCdp,
+    RemoveCollateral,
+    BurnFusd,
+}
+
+/// Amount of badges to present per operation. Operations without a configured amount use the full badge balance.
+#[derive(ScryptoSbor, Clone, Debug, Default)]
+pub struct BadgeAmounts {
+    pub amounts: IndexMap<BadgeOperation, Decimal>,
+}
+
+impl BadgeAmounts {
+    pub fn amount_for(&self, operation: BadgeOperation, badge_vault: &FungibleVault) -> Decimal {
+        self.amounts
+            .get(&operation)
+            .copied()
+            .unwrap_or_else(|| badge_vault.amount())
+    }
+}
+
+/// Kinds of owner actions that go through the timelock, each with its own delay.
+#[derive(ScryptoSbor, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ActionType {
+    SetOracle,
+    SetFine,
+    SetUsdPerFusd,
+    TakeUsdFromVault,
+    SendBadges,
+    RetrieveCollateralAdmin,
+    SetActionDelay,
+    SetUsdPerFusdBounds,
+    Migrate,
+    SetStrategy,
+    SetUsdPerFusdMaxChange,
+    SetTreasury,
+    SetCollateralGracePeriod,
+    SetOracleMethods,
+    SetRedemptionDiscount,
+    SetPegController,
+}
+
+impl ActionType {
+    // floor for set_action_delay, so no action can be made instant
+    pub fn min_delay(&self) -> i64 {
+        match self {
+            ActionType::Migrate => 604_800, // 7 days
+            ActionType::SendBadges
+            | ActionType::TakeUsdFromVault
+            | ActionType::SetTreasury
+            | ActionType::SetActionDelay
+            | ActionType::SetStrategy => 259_200, // 3 days
+            _ => 86_400,                    // 1 day
+        }
+    }
+}
+
+/// An owner action waiting in the timelock.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub enum PendingAction {
+    SetOracle {
+        oracle_address: ComponentAddress,
+        single_method_name: String,
+    },
+    SetFine {
+        fine: Decimal,
+    },
+    SetUsdPerFusd {
+        token: ResourceAddress,
+        usd_per_fusd: Decimal,
+    },
+    TakeUsdFromVault {
+        usd_token: ResourceAddress,
+        amount: Decimal,
+    },
+    SendBadges {
+        amount: Decimal,
+        receiver_address: ComponentAddress,
+    },
+    RetrieveCollateralAdmin {
+        cdp_id: NonFungibleLocalId,
+    },
+    SetActionDelay {
+        action_type: ActionType,
+        delay: i64,
+    },
+    SetUsdPerFusdBounds {
+        min: Decimal,
+        max: Decimal,
+    },
+    Migrate {
+        successor_address: ComponentAddress,
+    },
+    SetStrategy {
+        token: ResourceAddress,
+        strategy: Option<StrategyConfig>,
+    },
+    SetUsdPerFusdMaxChange {
+        max_change: Decimal,
+    },
+    SetTreasury {
+        treasury_address: ComponentAddress,
+        single_method_name: String,
+    },
+    SetCollateralGracePeriod {
+        grace_period: i64,
+    },
+    SetOracleMethods {
+        method_names: Vec<String>,
+    },
+    SetRedemptionDiscount {
+        redemption_discount: Decimal,
+    },
+    SetPegController {
+        token: ResourceAddress,
+        peg_controller: Option<PegController>,
+    },
+}
+
+impl PendingAction {
+    pub fn action_type(&self) -> ActionType {
+        match self {
+            PendingAction::SetOracle { .. } => ActionType::SetOracle,
+            PendingAction::SetFine { .. } => ActionType::SetFine,
+            PendingAction::SetUsdPerFusd { .. } => ActionType::SetUsdPerFusd,
+            PendingAction::TakeUsdFromVault { .. } => ActionType::TakeUsdFromVault,
+            PendingAction::SendBadges { .. } => ActionType::SendBadges,
+            PendingAction::RetrieveCollateralAdmin { .. } => ActionType::RetrieveCollateralAdmin,
+            PendingAction::SetActionDelay { .. } => ActionType::SetActionDelay,
+            PendingAction::SetUsdPerFusdBounds { .. } => ActionType::SetUsdPerFusdBounds,
+            PendingAction::Migrate { .. } => ActionType::Migrate,
+            PendingAction::SetStrategy { .. } => ActionType::SetStrategy,
+            PendingAction::SetUsdPerFusdMaxChange { .. } => ActionType::SetUsdPerFusdMaxChange,
+            PendingAction::SetTreasury { .. } => ActionType::SetTreasury,
+            PendingAction::SetCollateralGracePeriod { .. } => ActionType::SetCollateralGracePeriod,
+            PendingAction::SetOracleMethods { .. } => ActionType::SetOracleMethods,
+            PendingAction::SetRedemptionDiscount { .. } => ActionType::SetRedemptionDiscount,
+            PendingAction::SetPegController { .. } => ActionType::SetPegController,
+        }
+    }
+}
+
+/// A pending action together with the earliest moment it can be executed.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct QueuedAction {
+    pub action: PendingAction,
+    pub executable_at: Instant,
+}
+
+/// A PSM token as handed over to a successor component during migration.
+/// The successor should check that `usd` holds `backing` of `resource_address`, and compare `backing_per_fusd`
+/// to `usd_per_fusd` to see whether the minted fUSD is fully backed.
+#[derive(ScryptoSbor)]
+pub struct MigratedUsdToken {
+    pub resource_address: ResourceAddress,
+    pub usd: Bucket,
+    /// Vault plus strategy funds, strategies have to be emptied before migrating so all of it is in `usd`.
+    pub backing: Decimal,
+    /// Backing excluding the surplus per minted fUSD, None if no fUSD is minted against the token.
+    pub backing_per_fusd: Option<Decimal>,
+    pub accepted: bool,
     pub usd_per_fusd: Decimal,
     pub fusd_minted: Decimal,
+    pub mint_limit: Option<RateLimit>,
+    pub redeem_limit: Option<RateLimit>,
+    pub access: PsmAccess,
+    pub fx_priced: bool,
+    pub surplus: Decimal,
+}
+
+/// Everything handed over to a successor through its `receive_migration(migration: Migration)` method.
+/// Left in the old component are:
+/// - parked collateral and collateral claims, which stay retrievable and sweepable there
+/// - pending timelock actions, recorded bad debt, liquidator statistics and the overall statistics
+/// - the peg controller, strategy and oracle configuration, which the successor has to set up again
+/// - empty vaults, which can't be dropped
+#[derive(ScryptoSbor)]
+pub struct Migration {
+    pub usd_tokens: Vec<MigratedUsdToken>,
+    pub badges: Bucket,
+    pub badge_amount: Decimal,
+    pub stability_pool_units: Vec<MigratedStabilityPoolUnits>,
+    /// fUSD minted for stability pool top-ups and not yet redeemed, included in the tokens' `fusd_minted`.
+    pub stability_pool_top_up_total: Decimal,
+}
+
+/// Stability pool units from top-ups, as handed over to a successor during migration.
+#[derive(ScryptoSbor)]
+pub struct MigratedStabilityPoolUnits {
+    pub collateral: ResourceAddress,
+    pub pool_units: Bucket,
+    pub funding_token: ResourceAddress,
+    pub fusd_contributed: Decimal,
+}
+
+/// Pool units held in one collateral's stability pool, together with the PSM token whose surplus paid for them.
+#[derive(ScryptoSbor)]
+pub struct StabilityPoolPosition {
+    pub pool_units: FungibleVault,
+    pub funding_token: ResourceAddress,
+    /// fUSD minted for the units still held, included in the funding token's `fusd_minted`.
+    pub fusd_contributed: Decimal,
+}
+
+/// All-time counters kept in component state, returned by `get_stats`.
+/// fUSD volumes are keyed by PSM token, collateral amounts by collateral resource.
+#[derive(ScryptoSbor, Clone, Debug, Default)]
+pub struct Stats {
+    /// fUSD minted through the PSM, per PSM token.
+    pub psm_mint_volume: IndexMap<ResourceAddress, Decimal>,
+    /// fUSD redeemed through the PSM, per PSM token.
+    pub psm_redeem_volume: IndexMap<ResourceAddress, Decimal>,
+    /// Number of dust CDPs forcibly closed, including closes for fallback redemptions.
+    pub forced_closes: u64,
+    pub partial_liquidations: u64,
+    /// Collateral paid out to partial liquidators, per collateral.
+    pub partial_liquidation_collateral_paid: IndexMap<ResourceAddress, Decimal>,
+    /// Collateral paid out on top of the repaid debt's value, per collateral.
+    pub fines_captured: IndexMap<ResourceAddress, Decimal>,
+    /// fUSD burned as flash mint fees.
+    pub flash_mint_fees: Decimal,
+    pub bad_debt_write_offs: u64,
+    /// fUSD minted against PSM reserves and deposited into stability pools, per collateral.
+    pub stability_pool_top_ups: IndexMap<ResourceAddress, Decimal>,
+    /// CDP debt repaid with PSM reserves when writing off bad debt.
+    pub bad_debt_repaid: Decimal,
+}
+
+impl Stats {
+    fn add(map: &mut IndexMap<ResourceAddress, Decimal>, key: ResourceAddress, amount: Decimal) {
+        *map.entry(key).or_insert(Decimal::ZERO) += amount;
+    }
+
+    pub fn record_psm_mint(&mut self, token: ResourceAddress, fusd_amount: Decimal) {
+        Self::add(&mut self.psm_mint_volume, token, fusd_amount);
+    }
+
+    pub fn record_psm_redeem(&mut self, token: ResourceAddress, fusd_amount: Decimal) {
+        Self::add(&mut self.psm_redeem_volume, token, fusd_amount);
+    }
+
+    pub fn record_forced_close(&mut self, collateral: ResourceAddress, fine: Decimal) {
+        self.forced_closes += 1;
+        Self::add(
+            &mut self.fines_captured,
+            collateral,
+            fine.max(Decimal::ZERO),
+        );
+    }
+
+    pub fn record_stability_pool_top_up(
+        &mut self,
+        collateral: ResourceAddress,
+        fusd_amount: Decimal,
+    ) {
+        Self::add(&mut self.stability_pool_top_ups, collateral, fusd_amount);
+    }
+
+    pub fn record_partial_liquidation(
+        &mut self,
+        collateral: ResourceAddress,
+        collateral_paid: Decimal,
+        fine: Decimal,
+    ) {
+        self.partial_liquidations += 1;
+        Self::add(
+            &mut self.partial_liquidation_collateral_paid,
+            collateral,
+            collateral_paid,
+        );
+        Self::add(
+            &mut self.fines_captured,
+            collateral,
+            fine.max(Decimal::ZERO),
+        );
+    }
+}
+
+/// Optional identity a liquidator passes along, so their liquidations can be attributed to them.
+#[derive(ScryptoSbor)]
+pub enum LiquidatorIdentity {
+    /// Proof of any non-fungible badge, the liquidator is identified by its global id.
+    Badge(NonFungibleProof),
+    /// An account address, no proof needed since attributing volume to someone else gains nothing.
+    Account(ComponentAddress),
+}
+
+/// Key under which liquidator statistics are stored.
+#[derive(ScryptoSbor, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LiquidatorId {
+    Badge(NonFungibleGlobalId),
+    Account(ComponentAddress),
+}
+
+/// Per-liquidator totals, usable for running incentive programs from on-ledger data.
+#[derive(ScryptoSbor, Clone, Debug, Default)]
+pub struct LiquidatorStats {
+    /// Number of forced closes and partial liquidations performed.
+    pub liquidations: u64,
+    pub fusd_repaid: Decimal,
+    /// Collateral and stablecoin bonuses received, per resource.
+    pub earned: IndexMap<ResourceAddress, Decimal>,
+}
+
+/// Liquidation data for a single CDP, as returned by `find_liquidatable`.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct LiquidationCandidate {
+    pub cdp_id: NonFungibleLocalId,
+    pub collateral_address: ResourceAddress,
+    pub liquidatable: bool,
+    pub real_debt: Decimal,
+    pub fusd_in_stability_pool: Decimal,
+    /// Largest repayment for which the fine can still be paid out of the CDP's collateral, zero if partial liquidation isn't possible.
+    pub max_profitable_repay: Decimal,
 }
 
 /// Status of a CDP
@@ -47,6 +695,101 @@ pub struct Cdp {
     pub privileged_borrower: Option<NonFungibleLocalId>,
 }
 
+/// Data struct of a collateral claim NFT, minted for surplus collateral when requested by the CDP holder.
+/// The claim is transferable and can be redeemed for the collateral without holding the CDP receipt.
+#[derive(ScryptoSbor, NonFungibleData, Clone, Debug)]
+pub struct CollateralClaim {
+    /// Local ID of the CDP the surplus collateral came from.
+    pub cdp_id: NonFungibleLocalId,
+    /// The resource address of the claimable collateral.
+    pub collateral_address: ResourceAddress,
+    /// The amount of collateral that can be claimed with this NFT.
+    pub amount: Decimal,
+}
+
+/// Surplus collateral parked for a CDP, claimable by the CDP holder.
+#[derive(ScryptoSbor)]
+pub struct ParkedCollateral {
+    pub vault: FungibleVault,
+    /// Timestamp of the last time collateral was parked for this CDP.
+    pub parked_at: Instant,
+    /// Fixed when parking, so later grace period changes don't shorten it.
+    pub claimable_until: Instant,
+}
+
+/// Data struct of a flash mint receipt. The receipt cannot be deposited, so it has to be returned
+/// together with `amount + fee` fUSD within the same transaction.
+#[derive(ScryptoSbor, NonFungibleData, Clone, Debug)]
+pub struct FlashMintReceipt {
+    /// The amount of fUSD that was flash minted.
+    pub amount: Decimal,
+    /// The fee in fUSD that has to be paid on top of the amount.
+    pub fee: Decimal,
+}
+
+/// A structure for returning collateral parked for a CDP.
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct RetrievableCollateralInfo {
+    /// Local ID of the CDP the collateral is parked for.
+    pub cdp_id: NonFungibleLocalId,
+    /// The resource address of the parked collateral.
+    pub collateral_address: ResourceAddress,
+    /// The amount of parked collateral.
+    pub amount: Decimal,
+    /// Until this moment only the CDP holder can retrieve the collateral, afterwards it can be swept to the treasury.
+    pub claimable_until: Instant,
+}
+
+/// Emitted when an owner action is queued, so it can be reviewed before it executes.
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct ActionQueuedEvent {
+    pub action_id: u64,
+    pub action: PendingAction,
+    pub executable_at: Instant,
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct ActionExecutedEvent {
+    pub action_id: u64,
+    pub action: PendingAction,
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct ActionCancelledEvent {
+    pub action_id: u64,
+    pub action: PendingAction,
+}
+
+/// Emitted when all PSM vaults, accounting and badges are moved to a successor component.
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct MigratedEvent {
+    pub successor_address: ComponentAddress,
+    pub usd_tokens: Vec<ResourceAddress>,
+    pub badge_amount: Decimal,
+}
+
+/// Emitted when unclaimed collateral is swept to the treasury after its grace period.
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct CollateralSweptEvent {
+    pub cdp_id: NonFungibleLocalId,
+    pub collateral_address: ResourceAddress,
+    pub amount: Decimal,
+}
+
+/// Emitted when a CDP's bad debt is repaid with PSM reserves.
+#[derive(ScryptoSbor, ScryptoEvent, Clone, Debug)]
+pub struct BadDebtWrittenOffEvent {
+    pub cdp_id: NonFungibleLocalId,
+    /// Bad debt as recorded at the time of the write-off.
+    pub bad_debt: Decimal,
+    pub debt_repaid: Decimal,
+    pub usd_token: ResourceAddress,
+    pub usd_used: Decimal,
+    pub collateral_address: ResourceAddress,
+    /// Residual collateral, sent to the treasury.
+    pub collateral_recovered: Decimal,
+}
+
 /// A structure for returning stability pool information, including current asset amounts.
 #[derive(ScryptoSbor, Clone)]
 pub struct StabilityPoolInfoReturn {
@@ -77,28 +820,113 @@ pub struct StabilityPoolInfoReturn {
 }
 
 #[blueprint]
-#[types(UsdToken, FungibleVault, ResourceAddress, NonFungibleLocalId)]
+#[types(
+    UsdTokenState,
+    ParkedCollateral,
+    FungibleVault,
+    ResourceAddress,
+    NonFungibleLocalId,
+    Global<Account>,
+    u64,
+    QueuedAction,
+    LiquidatorId,
+    LiquidatorStats,
+    StabilityPoolPosition,
+    Decimal
+)]
+#[events(
+    CollateralSweptEvent,
+    ActionQueuedEvent,
+    ActionExecutedEvent,
+    ActionCancelledEvent,
+    MigratedEvent,
+    BadDebtWrittenOffEvent
+)]
 mod flux_addition {
     enable_method_auth! {
+        roles {
+            guardian => updatable_by: [OWNER];
+            operator => updatable_by: [OWNER];
+        },
         methods {
             mint_with_usd => PUBLIC;
             redeem_with_fusd => PUBLIC;
             close_loan => PUBLIC;
             receive_badges => PUBLIC;
+            execute_action => PUBLIC;
+            get_pending_action => PUBLIC;
+            get_action_delays => PUBLIC;
+            get_oracle_methods => PUBLIC;
+            get_badge_amounts => PUBLIC;
             partial_liquidate_cdp => PUBLIC;
             put_usd_in_vault => PUBLIC;
             retrieve_collateral => PUBLIC;
+            retrieve_collateral_many => PUBLIC;
+            get_retrievable_collateral => PUBLIC;
+            set_surplus_account => PUBLIC;
+            get_surplus_account => PUBLIC;
+            claim_retrievable_collateral => PUBLIC;
+            redeem_collateral_claims => PUBLIC;
             get_usd_amount_in_vault => PUBLIC;
+            get_surplus => PUBLIC;
             get_collateral_price => PUBLIC;
+            get_remaining_mint_capacity => PUBLIC;
+            get_remaining_redeem_capacity => PUBLIC;
+            get_pause_status => PUBLIC;
+            get_migrated_to => PUBLIC;
+            get_stats => PUBLIC;
+            get_liquidator_stats => PUBLIC;
+            report_bad_debt => PUBLIC;
+            find_liquidatable => PUBLIC;
+            get_stability_pool_units => PUBLIC;
+            get_strategy => PUBLIC;
+            update_peg_spread => PUBLIC;
+            get_peg_spreads => PUBLIC;
+            get_psm_access => PUBLIC;
+            get_bad_debts => PUBLIC;
+            flash_mint => PUBLIC;
+            repay_flash_mint => PUBLIC;
+            redeem_with_dust_cdp => PUBLIC;
             set_usd_per_fusd => restrict_to: [OWNER];
             add_usd_token => restrict_to: [OWNER];
             toggle_usd_token_accepted => restrict_to: [OWNER];
             set_max_debt_before_close => restrict_to: [OWNER];
             set_fine => restrict_to: [OWNER];
             send_badges => restrict_to: [OWNER];
+            cancel_action => restrict_to: [OWNER];
+            set_action_delay => restrict_to: [OWNER];
+            set_badge_amount => restrict_to: [OWNER];
             set_oracle => restrict_to: [OWNER];
             take_usd_from_vault => restrict_to: [OWNER];
             retrieve_collateral_admin => restrict_to: [OWNER];
+            set_collateral_grace_period => restrict_to: [OWNER];
+            set_treasury => restrict_to: [OWNER];
+            set_mint_limit => restrict_to: [OWNER];
+            set_redeem_limit => restrict_to: [OWNER];
+            set_usd_per_fusd_bounds => restrict_to: [OWNER];
+            set_usd_per_fusd_max_change => restrict_to: [OWNER];
+            migrate => restrict_to: [OWNER];
+            set_flash_mint_fee => restrict_to: [OWNER];
+            set_flash_mint_cap => restrict_to: [OWNER];
+            set_redemption_discount => restrict_to: [OWNER];
+            set_bad_debt_funding_token => restrict_to: [OWNER];
+            write_off_bad_debt => restrict_to: [operator, OWNER];
+            set_stability_pool_top_up_cap => restrict_to: [OWNER];
+            top_up_stability_pool => restrict_to: [operator, OWNER];
+            redeem_stability_pool_units => restrict_to: [operator, OWNER];
+            set_strategy => restrict_to: [OWNER];
+            set_peg_controller => restrict_to: [OWNER];
+            set_psm_access => restrict_to: [OWNER];
+            deploy_to_strategy => restrict_to: [operator, OWNER];
+            withdraw_from_strategy => restrict_to: [operator, OWNER];
+            harvest_strategy => restrict_to: [operator, OWNER];
+            unpause => restrict_to: [OWNER];
+            owner_pause => restrict_to: [OWNER];
+            pause => restrict_to: [guardian, OWNER];
+            disable_usd_token => restrict_to: [guardian, OWNER];
+            tune_usd_per_fusd => restrict_to: [operator, OWNER];
+            rotate_oracle_method => restrict_to: [operator, OWNER];
+            set_oracle_methods => restrict_to: [OWNER];
         }
     }
 
@@ -119,6 +947,8 @@ mod flux_addition {
         //"package_tdx_2_1phluu3kccm6h30qj83z7tjxgj5yk0ppmx8w7tma4cmfhgv9upnv2wj", //stokenet
         StabilityPools {
             fn get_stability_pool_infos(&self, resource_addresses: Option<Vec<ResourceAddress>>) -> Vec<StabilityPoolInfoReturn>;
+            fn contribute_to_pool(&self, collateral: ResourceAddress, contribution: Bucket) -> Bucket;
+            fn withdraw_from_pool(&self, collateral: ResourceAddress, tokens: Bucket) -> (Bucket, Bucket);
         }
     }
 
@@ -135,15 +965,45 @@ mod flux_addition {
     );
 
     struct FluxAddition {
-        usd_tokens: KeyValueStore<ResourceAddress, UsdToken>,
-        retrievable_collateral: KeyValueStore<NonFungibleLocalId, FungibleVault>,
+        usd_tokens: KeyValueStore<ResourceAddress, UsdTokenState>,
+        usd_token_addresses: Vec<ResourceAddress>,
+        retrievable_collateral: KeyValueStore<NonFungibleLocalId, ParkedCollateral>,
+        collateral_grace_period: i64,
+        claimable_collateral: KeyValueStore<NonFungibleLocalId, FungibleVault>,
+        surplus_accounts: KeyValueStore<NonFungibleLocalId, Global<Account>>,
+        claim_manager: NonFungibleResourceManager,
         cdp_address: ResourceAddress,
         fine: Decimal,
         max_debt_before_close: Decimal,
         badge_vault: FungibleVault,
+        badge_amounts: BadgeAmounts,
         oracle: Global<AnyComponent>,
         oracle_method_name: String,
+        oracle_methods: Vec<String>,
         fusd_address: ResourceAddress,
+        treasury: Option<Global<AnyComponent>>,
+        treasury_method_name: String,
+        pending_actions: KeyValueStore<u64, QueuedAction>,
+        next_action_id: u64,
+        action_delays: IndexMap<ActionType, i64>,
+        usd_per_fusd_min: Decimal,
+        usd_per_fusd_max: Decimal,
+        usd_per_fusd_tune_limit: RateLimit,
+        paused: u8,
+        migrated_to: Option<ComponentAddress>,
+        flash_mint_manager: NonFungibleResourceManager,
+        flash_mint_fee: Decimal,
+        flash_mint_cap: Decimal,
+        flash_mint_outstanding: Decimal,
+        redemption_discount: Decimal,
+        stats: Stats,
+        liquidator_stats: KeyValueStore<LiquidatorId, LiquidatorStats>,
+        bad_debts: KeyValueStore<NonFungibleLocalId, Decimal>,
+        bad_debt_funding_token: Option<ResourceAddress>,
+        stability_pool_units: KeyValueStore<ResourceAddress, StabilityPoolPosition>,
+        stability_pool_collaterals: Vec<ResourceAddress>,
+        stability_pool_top_up_cap: Decimal,
+        stability_pool_top_up_total: Decimal,
     }
 
     impl FluxAddition {
@@ -152,12 +1012,19 @@ mod flux_addition {
             fusd_address: ResourceAddress,
             badge_address: ResourceAddress,
             controller_address: ResourceAddress,
+            guardian_address: ResourceAddress,
+            operator_address: ResourceAddress,
             oracle_address: ComponentAddress,
             oracle_method_name: String,
             initial_usd_token: ResourceAddress,
             initial_usd_per_fusd: Decimal,
         ) -> Global<FluxAddition> {
-            let usd_tokens: KeyValueStore<ResourceAddress, UsdToken> =
+            assert!(
+                initial_usd_per_fusd >= DEFAULT_USD_PER_FUSD_MIN
+                    && initial_usd_per_fusd <= DEFAULT_USD_PER_FUSD_MAX,
+                "usd_per_fusd out of bounds"
+            );
+            let usd_tokens: KeyValueStore<ResourceAddress, UsdTokenState> =
                 KeyValueStore::new_with_registered_type();
 
             let usd_token = UsdToken {
@@ -165,31 +1032,138 @@ mod flux_addition {
                 accepted: true,
                 usd_per_fusd: initial_usd_per_fusd,
                 fusd_minted: Decimal::ZERO,
+                mint_limit: None,
+                redeem_limit: None,
+                strategy: None,
+                deployed: Decimal::ZERO,
+                access: PsmAccess::Public,
+                fx_priced: false,
+                surplus: Decimal::ZERO,
+                peg_controller: None,
             };
 
-            usd_tokens.insert(initial_usd_token, usd_token);
+            usd_tokens.insert(initial_usd_token, UsdTokenState::V2(usd_token));
+
+            let (address_reservation, component_address) =
+                Runtime::allocate_component_address(FluxAddition::blueprint_id());
+
+            let claim_manager = ResourceBuilder::new_ruid_non_fungible::<CollateralClaim>(
+                OwnerRole::Fixed(rule!(require(controller_address))),
+            )
+            .metadata(metadata! {
+                init {
+                    "name" => "Flux Collateral Claim", updatable;
+                }
+            })
+            .mint_roles(mint_roles! {
+                minter => rule!(require(global_caller(component_address)));
+                minter_updater => rule!(deny_all);
+            })
+            .burn_roles(burn_roles! {
+                burner => rule!(require(global_caller(component_address)));
+                burner_updater => rule!(deny_all);
+            })
+            .create_with_no_initial_supply();
+
+            let flash_mint_manager = ResourceBuilder::new_ruid_non_fungible::<FlashMintReceipt>(
+                OwnerRole::Fixed(rule!(require(controller_address))),
+            )
+            .metadata(metadata! {
+                init {
+                    "name" => "Flux Flash Mint Receipt", updatable;
+                }
+            })
+            .mint_roles(mint_roles! {
+                minter => rule!(require(global_caller(component_address)));
+                minter_updater => rule!(deny_all);
+            })
+            .burn_roles(burn_roles! {
+                burner => rule!(require(global_caller(component_address)));
+                burner_updater => rule!(deny_all);
+            })
+            .deposit_roles(deposit_roles! {
+                depositor => rule!(deny_all);
+                depositor_updater => rule!(deny_all);
+            })
+            .create_with_no_initial_supply();
 
             Self {
                 usd_tokens,
+                usd_token_addresses: vec![initial_usd_token],
                 retrievable_collateral: KeyValueStore::new_with_registered_type(),
+                collateral_grace_period: 15_552_000, // 180 days
+                claimable_collateral: KeyValueStore::new_with_registered_type(),
+                surplus_accounts: KeyValueStore::new_with_registered_type(),
+                claim_manager,
                 cdp_address,
                 fine: dec!("1.1"),
                 max_debt_before_close: dec!("0.1"),
                 fusd_address,
                 badge_vault: FungibleVault::new(badge_address),
+                badge_amounts: BadgeAmounts::default(),
                 oracle: Global::from(oracle_address),
+                oracle_methods: vec![oracle_method_name.clone()],
                 oracle_method_name,
+                treasury: None,
+                treasury_method_name: String::new(),
+                pending_actions: KeyValueStore::new_with_registered_type(),
+                next_action_id: 0,
+                action_delays: indexmap! {
+                    ActionType::SetOracle => 172_800, // 2 days
+                    ActionType::SetFine => 172_800,
+                    ActionType::SetUsdPerFusd => 172_800,
+                    ActionType::TakeUsdFromVault => 604_800, // 7 days
+                    ActionType::SendBadges => 604_800,
+                    ActionType::RetrieveCollateralAdmin => 172_800,
+                    ActionType::SetActionDelay => 604_800,
+                    ActionType::SetUsdPerFusdBounds => 172_800,
+                    ActionType::Migrate => 1_209_600, // 14 days
+                    ActionType::SetStrategy => 604_800, // 7 days
+                    ActionType::SetUsdPerFusdMaxChange => 172_800,
+                    ActionType::SetTreasury => 604_800,
+                    ActionType::SetCollateralGracePeriod => 172_800,
+                    ActionType::SetOracleMethods => 172_800,
+                    ActionType::SetRedemptionDiscount => 172_800,
+                    ActionType::SetPegController => 172_800,
+                },
+                usd_per_fusd_min: DEFAULT_USD_PER_FUSD_MIN,
+                usd_per_fusd_max: DEFAULT_USD_PER_FUSD_MAX,
+                usd_per_fusd_tune_limit: RateLimit::new(dec!("0.01"), USD_PER_FUSD_TUNE_WINDOW),
+                paused: 0,
+                migrated_to: None,
+                flash_mint_manager,
+                flash_mint_fee: dec!("0.001"),
+                flash_mint_cap: Decimal::ZERO,
+                flash_mint_outstanding: Decimal::ZERO,
+                redemption_discount: dec!("0.02"),
+                stats: Stats::default(),
+                liquidator_stats: KeyValueStore::new_with_registered_type(),
+                bad_debts: KeyValueStore::new_with_registered_type(),
+                bad_debt_funding_token: None,
+                stability_pool_units: KeyValueStore::new_with_registered_type(),
+                stability_pool_collaterals: vec![],
+                stability_pool_top_up_cap: Decimal::ZERO,
+                stability_pool_top_up_total: Decimal::ZERO,
             }
             .instantiate()
             .prepare_to_globalize(OwnerRole::Fixed(rule!(require(controller_address))))
+            .roles(roles! {
+                guardian => rule!(require(guardian_address));
+                operator => rule!(require(operator_address));
+            })
+            .with_address(address_reservation)
             .globalize()
         }
 
-        pub fn add_usd_token(&mut self, token: ResourceAddress, usd_per_fusd: Decimal) {
-            assert!(
-                usd_per_fusd > Decimal::ZERO,
-                "usd_per_fusd must be positive"
-            );
+        // fx_priced can't be changed later, as it changes what usd_per_fusd means for the token
+        pub fn add_usd_token(
+            &mut self,
+            token: ResourceAddress,
+            usd_per_fusd: Decimal,
+            fx_priced: bool,
+        ) {
+            self.assert_not_migrated();
+            self.assert_usd_per_fusd_in_bounds(usd_per_fusd);
             assert!(
                 self.usd_tokens.get(&token).is_none(),
                 "Vault Already Exists"
@@ -200,30 +1174,106 @@ mod flux_addition {
                 accepted: true,
                 usd_per_fusd,
                 fusd_minted: Decimal::ZERO,
+                mint_limit: None,
+                redeem_limit: None,
+                strategy: None,
+                deployed: Decimal::ZERO,
+                access: PsmAccess::Public,
+                fx_priced,
+                surplus: Decimal::ZERO,
+                peg_controller: None,
             };
 
-            self.usd_tokens.insert(token, usd_token);
+            self.usd_tokens.insert(token, UsdTokenState::V2(usd_token));
+            self.usd_token_addresses.push(token);
         }
 
         pub fn toggle_usd_token_accepted(&mut self, token: ResourceAddress) {
-            let mut usd_token = self.usd_tokens.get_mut(&token).unwrap();
+            self.assert_not_migrated();
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
             usd_token.accepted = !usd_token.accepted;
         }
 
-        pub fn mint_with_usd(&mut self, usd: FungibleBucket) -> Bucket {
+        // the guardian can only disable, re-enabling is up to the owner
+        pub fn disable_usd_token(&mut self, token: ResourceAddress) {
+            self.usd_tokens.upgraded_mut(&token).latest_mut().accepted = false;
+        }
+
+        // flags is a combination of the PAUSE_* constants, use PAUSE_ALL to halt everything at once
+        pub fn pause(&mut self, flags: u8) {
+            assert!(
+                flags & !PAUSE_GUARDIAN == 0,
+                "Flag can only be paused by the owner"
+            );
+            self.paused |= flags;
+        }
+
+        // blocked after migration, so retrieval of collateral left behind can't be frozen for good
+        pub fn owner_pause(&mut self, flags: u8) {
+            self.assert_not_migrated();
+            assert!(flags & !PAUSE_ALL == 0, "Unknown pause flag");
+            self.paused |= flags;
+        }
+
+        pub fn unpause(&mut self, flags: u8) {
+            self.assert_not_migrated();
+            assert!(flags & !PAUSE_ALL == 0, "Unknown pause flag");
+            self.paused &= !flags;
+        }
+
+        pub fn get_pause_status(&self) -> PauseStatus {
+            PauseStatus {
+                flags: self.paused,
+                mint_with_usd: self.is_paused(PAUSE_MINT),
+                redeem_with_fusd: self.is_paused(PAUSE_REDEEM),
+                close_loan: self.is_paused(PAUSE_CLOSE_LOAN),
+                partial_liquidate_cdp: self.is_paused(PAUSE_PARTIAL_LIQUIDATION),
+                retrieve_collateral: self.is_paused(PAUSE_RETRIEVE_COLLATERAL),
+                flash_mint: self.is_paused(PAUSE_FLASH_MINT),
+            }
+        }
+
+        // message and signature are only needed for FX priced tokens
+        pub fn mint_with_usd(
+            &mut self,
+            usd: FungibleBucket,
+            min_fusd_out: Decimal,
+            message: Option<String>,
+            signature: Option<String>,
+        ) -> Bucket {
             let token = usd.resource_address();
-            let mut usd_token = self.usd_tokens.get_mut(&token).unwrap();
+            self.assert_not_paused(PAUSE_MINT);
+            let fx_price = self.fx_price(token, message, signature);
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
 
             assert!(usd_token.accepted, "Token not accepted");
+            usd_token.access.assert_access();
+
+            let mint_spread = usd_token
+                .peg_controller
+                .as_ref()
+                .map(|peg_controller| peg_controller.mint_spread())
+                .unwrap_or(Decimal::ZERO);
+            let fusd_amount =
+                usd.amount() * fx_price / usd_token.usd_per_fusd * (Decimal::ONE - mint_spread);
+            assert!(fusd_amount >= min_fusd_out, "fUSD out below minimum");
+            if let Some(mint_limit) = usd_token.mint_limit.as_mut() {
+                mint_limit.consume(fusd_amount, "Mint limit reached, try again later");
+            }
 
-            let fusd_amount = usd.amount() / usd_token.usd_per_fusd;
-            let badge_amount = self.badge_vault.amount();
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::FreeFusd, &self.badge_vault);
             let fusd = self
                 .badge_vault
                 .authorize_with_amount(badge_amount, || FLUX.free_fusd(fusd_amount));
 
+            usd_token.surplus += usd.amount() * mint_spread;
             usd_token.vault.put(usd);
             usd_token.fusd_minted += fusd.amount();
+            self.stats.record_psm_mint(token, fusd.amount());
 
             fusd
         }
@@ -232,53 +1282,162 @@ mod flux_addition {
             &mut self,
             mut fusd: FungibleBucket,
             against_token: ResourceAddress,
+            min_usd_out: Decimal,
+            message: Option<String>,
+            signature: Option<String>,
         ) -> (FungibleBucket, FungibleBucket) {
-            let mut usd_token = self.usd_tokens.get_mut(&against_token).unwrap();
+            self.assert_not_paused(PAUSE_REDEEM);
+            let fx_price = self.fx_price(against_token, message, signature);
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&against_token);
+            let usd_token = usd_token_state.latest_mut();
+            usd_token.access.assert_access();
             assert!(
                 usd_token.fusd_minted > Decimal::ZERO,
                 "No fUSD minted against this token"
             );
-            let max_usd_to_receive: Decimal = usd_token.vault.amount();
-            let usd_per_fusd: Decimal = usd_token.vault.amount() / usd_token.fusd_minted;
+            let max_usd_to_receive: Decimal =
+                (usd_token.vault.amount() - usd_token.surplus).max(Decimal::ZERO);
+            let redeem_spread = usd_token
+                .peg_controller
+                .as_ref()
+                .map(|peg_controller| peg_controller.redeem_spread())
+                .unwrap_or(Decimal::ZERO);
+            // FX priced tokens are redeemed at the current FX rate instead of the vault ratio
+            let base_usd_per_fusd: Decimal = if usd_token.fx_priced {
+                usd_token.usd_per_fusd / fx_price
+            } else {
+                (usd_token.backing() - usd_token.surplus) / usd_token.fusd_minted
+            };
+            let usd_per_fusd = base_usd_per_fusd * (Decimal::ONE - redeem_spread);
             let usd_to_receive = usd_per_fusd * fusd.amount();
+            assert!(
+                usd_to_receive.min(max_usd_to_receive) >= min_usd_out,
+                "USD out below minimum"
+            );
 
-            let badge_amount = self.badge_vault.amount();
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::BurnFusd, &self.badge_vault);
             if usd_to_receive > max_usd_to_receive {
                 let fusd_to_take = max_usd_to_receive / usd_per_fusd;
+                if let Some(redeem_limit) = usd_token.redeem_limit.as_mut() {
+                    redeem_limit.consume(fusd_to_take, "Redeem limit reached, try again later");
+                }
 
                 self.badge_vault.authorize_with_amount(badge_amount, || {
                     fusd.take(fusd_to_take).burn();
                 });
 
                 usd_token.fusd_minted -= fusd_to_take;
+                usd_token.surplus += fusd_to_take * base_usd_per_fusd * redeem_spread;
+                self.stats.record_psm_redeem(against_token, fusd_to_take);
 
                 (usd_token.vault.take(max_usd_to_receive), fusd)
             } else {
                 let fusd_amount = fusd.amount();
+                if let Some(redeem_limit) = usd_token.redeem_limit.as_mut() {
+                    redeem_limit.consume(fusd_amount, "Redeem limit reached, try again later");
+                }
                 self.badge_vault.authorize_with_amount(badge_amount, || {
                     fusd.take(fusd_amount).burn();
                 });
 
                 usd_token.fusd_minted -= fusd_amount;
+                usd_token.surplus += fusd_amount * base_usd_per_fusd * redeem_spread;
+                self.stats.record_psm_redeem(against_token, fusd_amount);
 
                 (usd_token.vault.take(usd_to_receive), fusd)
             }
         }
 
-        pub fn set_usd_per_fusd(&mut self, token: ResourceAddress, usd_per_fusd: Decimal) {
-            assert!(
-                usd_per_fusd > Decimal::ZERO,
-                "usd_per_fusd must be positive"
+        pub fn set_usd_per_fusd(&mut self, token: ResourceAddress, usd_per_fusd: Decimal) -> u64 {
+            self.assert_usd_per_fusd_in_bounds(usd_per_fusd);
+            assert!(self.usd_tokens.get(&token).is_some(), "Unknown token");
+            self.queue_action(PendingAction::SetUsdPerFusd {
+                token,
+                usd_per_fusd,
+            })
+        }
+
+        // immediate, but only within the bounds set through the timelock
+        // the relative changes of all tune calls within USD_PER_FUSD_TUNE_WINDOW add up to at most the max change,
+        // larger changes have to go through set_usd_per_fusd
+        pub fn tune_usd_per_fusd(&mut self, token: ResourceAddress, usd_per_fusd: Decimal) {
+            self.assert_not_migrated();
+            self.assert_usd_per_fusd_in_bounds(usd_per_fusd);
+
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
+            let relative_change = (usd_per_fusd - usd_token.usd_per_fusd)
+                .checked_abs()
+                .unwrap()
+                / usd_token.usd_per_fusd;
+            self.usd_per_fusd_tune_limit.consume(
+                relative_change,
+                "usd_per_fusd change too large, try again later",
             );
-            self.usd_tokens.get_mut(&token).unwrap().usd_per_fusd = usd_per_fusd;
+
+            usd_token.usd_per_fusd = usd_per_fusd;
+        }
+
+        pub fn set_psm_access(&mut self, token: ResourceAddress, access: PsmAccess) {
+            self.assert_not_migrated();
+            if let PsmAccess::Accounts(accounts) = &access {
+                for account_address in accounts {
+                    assert!(
+                        account_address.as_node_id().is_global_account(),
+                        "Not an account"
+                    );
+                }
+            }
+            self.usd_tokens.upgraded_mut(&token).latest_mut().access = access;
+        }
+
+        pub fn set_usd_per_fusd_bounds(&mut self, min: Decimal, max: Decimal) -> u64 {
+            assert!(min > Decimal::ZERO, "usd_per_fusd must be positive");
+            assert!(min <= max, "Minimum cannot be above maximum");
+            self.queue_action(PendingAction::SetUsdPerFusdBounds { min, max })
+        }
+
+        // relative to usd_per_fusd, so 0.01 allows tuning by 1% per USD_PER_FUSD_TUNE_WINDOW
+        pub fn set_usd_per_fusd_max_change(&mut self, max_change: Decimal) -> u64 {
+            assert!(max_change >= Decimal::ZERO, "Max change cannot be negative");
+            self.queue_action(PendingAction::SetUsdPerFusdMaxChange { max_change })
+        }
+
+        // a limit of None removes the limit for this token
+        pub fn set_mint_limit(
+            &mut self,
+            token: ResourceAddress,
+            max_amount: Option<Decimal>,
+            window_seconds: i64,
+        ) {
+            self.assert_not_migrated();
+            self.usd_tokens.upgraded_mut(&token).latest_mut().mint_limit =
+                max_amount.map(|max_amount| RateLimit::new(max_amount, window_seconds));
+        }
+
+        pub fn set_redeem_limit(
+            &mut self,
+            token: ResourceAddress,
+            max_amount: Option<Decimal>,
+            window_seconds: i64,
+        ) {
+            self.assert_not_migrated();
+            self.usd_tokens
+                .upgraded_mut(&token)
+                .latest_mut()
+                .redeem_limit =
+                max_amount.map(|max_amount| RateLimit::new(max_amount, window_seconds));
         }
 
-        pub fn set_fine(&mut self, fine: Decimal) {
+        pub fn set_fine(&mut self, fine: Decimal) -> u64 {
             assert!(fine >= Decimal::ONE, "Fine cannot be below 1");
-            self.fine = fine;
+            self.queue_action(PendingAction::SetFine { fine })
         }
 
         pub fn set_max_debt_before_close(&mut self, max_debt_before_close: Decimal) {
+            self.assert_not_migrated();
             self.max_debt_before_close = max_debt_before_close;
         }
 
@@ -288,7 +1447,12 @@ mod flux_addition {
             fusd: Bucket,
             message: String,
             signature: String,
-        ) -> (Bucket, Option<Bucket>) {
+            cdp_proof: Option<NonFungibleProof>,
+            liquidator: Option<LiquidatorIdentity>,
+        ) -> (Bucket, Option<Bucket>, Option<Bucket>) {
+            self.assert_not_paused(PAUSE_CLOSE_LOAN);
+            let mint_claim = self.check_optional_cdp_proof(cdp_proof, &cdp_id);
+            let liquidator_id = liquidator.map(|liquidator| self.liquidator_id(liquidator));
             let cdp_manager: NonFungibleResourceManager =
                 NonFungibleResourceManager::from(self.cdp_address);
             let receipt_data: Cdp = cdp_manager.get_non_fungible_data(&cdp_id);
@@ -299,32 +1463,81 @@ mod flux_addition {
             );
 
             let fusd_input_amount: Decimal = fusd.amount();
-            let badge_amount = self.badge_vault.amount();
-
-            let (mut collateral, leftover_fusd): (Bucket, Bucket) = self
-                .badge_vault
-                .authorize_with_amount(badge_amount, || FLUX.close_cdp(cdp_id.clone(), fusd));
-            let fusd_spent: Decimal = fusd_input_amount - leftover_fusd.amount();
-
-            assert!(
-                fusd_spent < self.max_debt_before_close,
-                "Too much debt to forcibly close."
+            let (collateral, leftover_fusd, claim) = self.force_close_cdp(
+                cdp_id,
+                fusd,
+                collateral_price,
+                self.fine,
+                self.max_debt_before_close,
+                mint_claim,
             );
 
-            let collateral_reward_max = (fusd_spent / collateral_price) * self.fine;
-            let collateral_surplus = collateral.amount() - collateral_reward_max;
-
-            if collateral_surplus > Decimal::ZERO {
-                let surplus_bucket = collateral.take(collateral_surplus);
-                self.put_retrievable_collateral(cdp_id, surplus_bucket.as_fungible());
+            if let Some(liquidator_id) = liquidator_id {
+                let leftover_fusd_amount = leftover_fusd
+                    .as_ref()
+                    .map(|leftover_fusd| leftover_fusd.amount())
+                    .unwrap_or(Decimal::ZERO);
+                self.record_liquidation(
+                    liquidator_id,
+                    fusd_input_amount - leftover_fusd_amount,
+                    &[&collateral],
+                );
             }
 
-            if leftover_fusd.amount() > Decimal::ZERO {
-                (collateral, Some(leftover_fusd))
-            } else {
-                leftover_fusd.drop_empty();
-                (collateral, None)
-            }
+            (collateral, leftover_fusd, claim)
+        }
+
+        // fallback redemption for when the PSM has no stables left: fUSD holders can close the same dust CDPs as
+        // close_loan and receive the collateral at the oracle price minus the redemption discount
+        pub fn redeem_with_dust_cdp(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            fusd: Bucket,
+            message: String,
+            signature: String,
+        ) -> (Bucket, Option<Bucket>) {
+            self.assert_not_paused(PAUSE_REDEEM);
+            self.assert_not_paused(PAUSE_CLOSE_LOAN);
+            assert!(
+                self.usd_token_addresses.iter().all(|token| {
+                    let mut usd_token_state = self.usd_tokens.upgraded_mut(token);
+                    usd_token_state.latest_mut().redemptions_exhausted()
+                }),
+                "PSM still holds stables, use redeem_with_fusd"
+            );
+
+            let receipt_data: Cdp =
+                NonFungibleResourceManager::from(self.cdp_address).get_non_fungible_data(&cdp_id);
+            let collateral_price: Decimal = self.oracle.call_raw(
+                &self.oracle_method_name,
+                scrypto_args!(receipt_data.collateral_address, message, signature),
+            );
+
+            // never pays more than close_loan, also if the fine was lowered after the discount was set
+            let payout_multiplier =
+                (Decimal::ONE / (Decimal::ONE - self.redemption_discount)).min(self.fine);
+            let (collateral, leftover_fusd, _) = self.force_close_cdp(
+                cdp_id,
+                fusd,
+                collateral_price,
+                payout_multiplier,
+                self.max_debt_before_close,
+                false,
+            );
+
+            (collateral, leftover_fusd)
+        }
+
+        // the payout stays within the fine, so redeemers never get more than close_loan pays
+        pub fn set_redemption_discount(&mut self, redemption_discount: Decimal) -> u64 {
+            assert!(
+                redemption_discount >= Decimal::ZERO
+                    && redemption_discount <= Decimal::ONE - Decimal::ONE / self.fine,
+                "Discount must be between 0 and 1 - 1 / fine"
+            );
+            self.queue_action(PendingAction::SetRedemptionDiscount {
+                redemption_discount,
+            })
         }
 
         pub fn partial_liquidate_cdp(
@@ -334,7 +1547,13 @@ mod flux_addition {
             message: String,
             signature: String,
             price_multiplier_for_removal: Decimal,
-        ) -> (Bucket, Bucket) {
+            cdp_proof: Option<NonFungibleProof>,
+            liquidator: Option<LiquidatorIdentity>,
+            bonus_token: Option<ResourceAddress>,
+        ) -> (Bucket, Bucket, Option<Bucket>, Option<Bucket>) {
+            self.assert_not_paused(PAUSE_PARTIAL_LIQUIDATION);
+            let mint_claim = self.check_optional_cdp_proof(cdp_proof, &cdp_id);
+            let liquidator_id = liquidator.map(|liquidator| self.liquidator_id(liquidator));
             let cdp_manager: NonFungibleResourceManager =
                 NonFungibleResourceManager::from(self.cdp_address);
             let receipt_data: Cdp = cdp_manager.get_non_fungible_data(&cdp_id);
@@ -357,7 +1576,9 @@ mod flux_addition {
             assert!(liquidatable, "This CDP cannot be liquidated");
 
             let fusd_input_amount: Decimal = fusd.amount();
-            let badge_amount = self.badge_vault.amount();
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::PartialCloseCdp, &self.badge_vault);
 
             let (collateral, leftover_fusd): (Option<Bucket>, Option<Bucket>) =
                 self.badge_vault.authorize_with_amount(badge_amount, || {
@@ -388,96 +1609,1051 @@ mod flux_addition {
             let collateral_to_take_out_to_reach_min =
                 receipt_data_after_close.collateral_amount - (Decimal::ONE / collateral_price); //collateral available - min collateral in cdp (we want at least 1 dollar in there)
 
-            let extra_collateral: Bucket = if collateral_shortage > Decimal::ZERO {
-                let amount_to_remove =
-                    collateral_shortage.min(collateral_to_take_out_to_reach_min.max(Decimal::ZERO));
-
-                let high_price = collateral_price * price_multiplier_for_removal;
-                if amount_to_remove > Decimal::ZERO {
-                    self.badge_vault.authorize_with_amount(badge_amount, || {
-                        FLUX.remove_collateral(cdp_id.clone(), amount_to_remove, Some(high_price))
-                    })
-                } else {
+            let mut claim: Option<Bucket> = None;
+            let mut bonus: Option<Bucket> = None;
+            let extra_collateral: Bucket = match collateral_shortage.cmp(&Decimal::ZERO) {
+                // the bonus is paid in a stablecoin instead, so the cdp keeps its collateral
+                Ordering::Greater if bonus_token.is_some() => {
+                    bonus = Some(
+                        self.take_stable_bonus(
+                            bonus_token.unwrap(),
+                            collateral_shortage * collateral_price,
+                        )
+                        .into(),
+                    );
                     Bucket::new(receipt_data.collateral_address)
                 }
-            } else if collateral_shortage < Decimal::ZERO {
-                let collateral_surplus = collateral_shortage.checked_abs().unwrap();
-                self.put_retrievable_collateral(
-                    cdp_id,
-                    collateral_bucket.take(collateral_surplus).as_fungible(),
-                );
-                Bucket::new(receipt_data.collateral_address)
-            } else {
-                Bucket::new(receipt_data.collateral_address)
+                Ordering::Greater => {
+                    let amount_to_remove = collateral_shortage
+                        .min(collateral_to_take_out_to_reach_min.max(Decimal::ZERO));
+
+                    let high_price = collateral_price * price_multiplier_for_removal;
+                    if amount_to_remove > Decimal::ZERO {
+                        let badge_amount = self
+                            .badge_amounts
+                            .amount_for(BadgeOperation::RemoveCollateral, &self.badge_vault);
+                        self.badge_vault.authorize_with_amount(badge_amount, || {
+                            FLUX.remove_collateral(
+                                cdp_id.clone(),
+                                amount_to_remove,
+                                Some(high_price),
+                            )
+                        })
+                    } else {
+                        Bucket::new(receipt_data.collateral_address)
+                    }
+                }
+                Ordering::Less => {
+                    let collateral_surplus = collateral_shortage.checked_abs().unwrap();
+                    claim = self.park_surplus_collateral(
+                        cdp_id.clone(),
+                        collateral_bucket.take(collateral_surplus).as_fungible(),
+                        mint_claim,
+                    );
+                    Bucket::new(receipt_data.collateral_address)
+                }
+                Ordering::Equal => Bucket::new(receipt_data.collateral_address),
             };
 
             collateral_bucket.put(extra_collateral);
 
-            (collateral_bucket, leftover_fusd_bucket)
+            if collateral_to_take_out_to_reach_min <= Decimal::ZERO {
+                self.update_bad_debt(cdp_id.clone(), collateral_price);
+            }
+
+            self.stats.record_partial_liquidation(
+                receipt_data.collateral_address,
+                collateral_bucket.amount(),
+                collateral_bucket.amount() - fusd_spent / collateral_price,
+            );
+            if let Some(liquidator_id) = liquidator_id {
+                let mut earned = vec![&collateral_bucket];
+                earned.extend(bonus.as_ref());
+                self.record_liquidation(liquidator_id, fusd_spent, &earned);
+            }
+
+            (collateral_bucket, leftover_fusd_bucket, claim, bonus)
         }
 
-        pub fn receive_badges(&mut self, badge_bucket: Bucket) {
-            self.badge_vault.put(badge_bucket.as_fungible());
+        // records or clears the bad debt of a cdp at the current oracle price, returns the bad debt
+        pub fn report_bad_debt(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            message: String,
+            signature: String,
+        ) -> Decimal {
+            let cdp_manager: NonFungibleResourceManager =
+                NonFungibleResourceManager::from(self.cdp_address);
+            let receipt_data: Cdp = cdp_manager.get_non_fungible_data(&cdp_id);
+            let collateral_price: Decimal = self.oracle.call_raw(
+                &self.oracle_method_name,
+                scrypto_args!(receipt_data.collateral_address, message, signature),
+            );
+
+            self.update_bad_debt(cdp_id, collateral_price)
         }
 
-        pub fn set_oracle(&mut self, oracle_address: ComponentAddress, single_method_name: String) {
-            self.oracle = Global::from(oracle_address);
-            self.oracle_method_name = single_method_name;
+        // None stops write-offs
+        pub fn set_bad_debt_funding_token(&mut self, usd_token: Option<ResourceAddress>) {
+            self.assert_not_migrated();
+            if let Some(usd_token) = usd_token {
+                assert!(self.usd_tokens.get(&usd_token).is_some(), "Token not found");
+            }
+            self.bad_debt_funding_token = usd_token;
         }
 
-        pub fn send_badges(&mut self, amount: Decimal, receiver_address: ComponentAddress) {
-            let receiver: Global<AnyComponent> = Global::from(receiver_address);
-            let badge_bucket: Bucket = self.badge_vault.take(amount).into();
-            receiver.call_raw("receive_badges", scrypto_args!(badge_bucket))
+        // repays the full debt of a cdp with recorded bad debt using fUSD backed by PSM surplus,
+        // the residual collateral goes to the treasury
+        pub fn write_off_bad_debt(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            message: String,
+            signature: String,
+        ) {
+            self.assert_not_migrated();
+            let usd_token_address = self
+                .bad_debt_funding_token
+                .expect("No funding token set for bad debt write-offs");
+
+            let bad_debt = self.report_bad_debt(cdp_id.clone(), message, signature);
+            assert!(bad_debt > Decimal::ZERO, "CDP has no bad debt");
+
+            let (_liquidatable, real_debt, collateral_address) =
+                FLUX.check_liquidate_cdp(cdp_id.clone(), None);
+
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&usd_token_address);
+            let usd_token = usd_token_state.latest_mut();
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::FreeFusd, &self.badge_vault);
+            let fusd = self
+                .badge_vault
+                .authorize_with_amount(badge_amount, || FLUX.free_fusd(real_debt));
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::CloseCdp, &self.badge_vault);
+            let (collateral, leftover_fusd): (Bucket, Bucket) = self
+                .badge_vault
+                .authorize_with_amount(badge_amount, || FLUX.close_cdp(cdp_id.clone(), fusd));
+
+            // the minted fUSD is now backed by what was surplus
+            let debt_repaid = real_debt - leftover_fusd.amount();
+            let usd_used = debt_repaid * usd_token.usd_per_fusd;
+            usd_token.spend_surplus(usd_used);
+            usd_token.fusd_minted += debt_repaid;
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::BurnFusd, &self.badge_vault);
+            self.badge_vault
+                .authorize_with_amount(badge_amount, || leftover_fusd.burn());
+            drop(usd_token_state);
+
+            self.bad_debts.remove(&cdp_id);
+            self.stats.bad_debt_write_offs += 1;
+            self.stats.bad_debt_repaid += debt_repaid;
+
+            Runtime::emit_event(BadDebtWrittenOffEvent {
+                cdp_id,
+                bad_debt,
+                debt_repaid,
+                usd_token: usd_token_address,
+                usd_used,
+                collateral_address,
+                collateral_recovered: collateral.amount(),
+            });
+
+            if collateral.amount() > Decimal::ZERO {
+                self.send_to_treasury(collateral);
+            } else {
+                collateral.drop_empty();
+            }
+        }
+
+        // caps the fUSD minted for stability pool top-ups that hasn't been redeemed yet
+        pub fn set_stability_pool_top_up_cap(&mut self, cap: Decimal) {
+            self.assert_not_migrated();
+            assert!(cap >= Decimal::ZERO, "Cap must be positive");
+            self.stability_pool_top_up_cap = cap;
         }
 
-        pub fn take_usd_from_vault(
+        // mints fUSD backed by surplus of usd_token into the collateral's stability pool,
+        // the pool units received are held per collateral, each collateral is funded by one token at a time
+        pub fn top_up_stability_pool(
             &mut self,
+            collateral: ResourceAddress,
             usd_token: ResourceAddress,
             amount: Decimal,
+        ) {
+            self.assert_not_migrated();
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            assert!(
+                self.stability_pool_top_up_total + amount <= self.stability_pool_top_up_cap,
+                "Amount above stability pool top-up cap"
+            );
+
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&usd_token);
+            let usd_token_data = usd_token_state.latest_mut();
+            usd_token_data.spend_surplus(amount * usd_token_data.usd_per_fusd);
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::FreeFusd, &self.badge_vault);
+            let fusd = self
+                .badge_vault
+                .authorize_with_amount(badge_amount, || FLUX.free_fusd(amount));
+            usd_token_data.fusd_minted += amount;
+            drop(usd_token_state);
+
+            let pool_units = STABILITY_POOLS.contribute_to_pool(collateral, fusd);
+
+            let position_exists = self.stability_pool_units.get(&collateral).is_some();
+            if position_exists {
+                let mut position = self.stability_pool_units.get_mut(&collateral).unwrap();
+                if position.pool_units.amount() == Decimal::ZERO {
+                    position.funding_token = usd_token;
+                }
+                assert!(
+                    position.funding_token == usd_token,
+                    "Pool units for this collateral are funded by another token"
+                );
+                position.pool_units.put(pool_units.as_fungible());
+                position.fusd_contributed += amount;
+            } else {
+                self.stability_pool_units.insert(
+                    collateral,
+                    StabilityPoolPosition {
+                        pool_units: FungibleVault::with_bucket(pool_units.as_fungible()),
+                        funding_token: usd_token,
+                        fusd_contributed: amount,
+                    },
+                );
+                self.stability_pool_collaterals.push(collateral);
+            }
+
+            self.stability_pool_top_up_total += amount;
+            self.stats.record_stability_pool_top_up(collateral, amount);
+        }
+
+        // withdraws pool units from the stability pool and settles their share of the top-ups with the funding token.
+        // fUSD burned by liquidations no longer exists either, so the whole share is taken off fusd_minted and its
+        // backing becomes surplus again. the fUSD received is burned, collateral from liquidations goes to the treasury
+        pub fn redeem_stability_pool_units(
+            &mut self,
+            collateral: ResourceAddress,
+            amount: Decimal,
+        ) {
+            self.assert_not_migrated();
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            let mut position = self
+                .stability_pool_units
+                .get_mut(&collateral)
+                .expect("No pool units for this collateral");
+            assert!(
+                amount <= position.pool_units.amount(),
+                "Not enough pool units for this collateral"
+            );
+            let fusd_share = position.fusd_contributed * amount / position.pool_units.amount();
+            let pool_units = position.pool_units.take(amount);
+            position.fusd_contributed -= fusd_share;
+            let funding_token = position.funding_token;
+            drop(position);
+
+            let (fusd, collateral_bucket): (Bucket, Bucket) =
+                STABILITY_POOLS.withdraw_from_pool(collateral, pool_units.into());
+
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&funding_token);
+            let usd_token_data = usd_token_state.latest_mut();
+
+            // released at the current backing ratio, so the ratio for other fUSD holders doesn't change
+            let settled = fusd_share.min(usd_token_data.fusd_minted);
+            if settled > Decimal::ZERO {
+                let released = settled * (usd_token_data.backing() - usd_token_data.surplus)
+                    / usd_token_data.fusd_minted;
+                usd_token_data.fusd_minted -= settled;
+                usd_token_data.surplus += released;
+            }
+            drop(usd_token_state);
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::BurnFusd, &self.badge_vault);
+            self.badge_vault
+                .authorize_with_amount(badge_amount, || fusd.burn());
+
+            self.stability_pool_top_up_total =
+                (self.stability_pool_top_up_total - fusd_share).max(Decimal::ZERO);
+
+            if collateral_bucket.amount() > Decimal::ZERO {
+                self.send_to_treasury(collateral_bucket);
+            } else {
+                collateral_bucket.drop_empty();
+            }
+        }
+
+        // the receipt can't be deposited, so the transaction only succeeds if repay_flash_mint is called
+        pub fn flash_mint(&mut self, amount: Decimal) -> (Bucket, Bucket) {
+            self.assert_not_paused(PAUSE_FLASH_MINT);
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            // the cap applies to everything flash minted and not yet repaid, so it can't be stacked within a transaction
+            assert!(
+                self.flash_mint_outstanding + amount <= self.flash_mint_cap,
+                "Amount above flash mint cap"
+            );
+            self.flash_mint_outstanding += amount;
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::FreeFusd, &self.badge_vault);
+            let fusd = self
+                .badge_vault
+                .authorize_with_amount(badge_amount, || FLUX.free_fusd(amount));
+
+            let receipt = self
+                .flash_mint_manager
+                .mint_ruid_non_fungible(FlashMintReceipt {
+                    amount,
+                    fee: amount * self.flash_mint_fee,
+                });
+
+            (fusd, receipt.into())
+        }
+
+        // burns the flash minted fUSD and the fee, returns what is left of the payment
+        pub fn repay_flash_mint(
+            &mut self,
+            mut fusd: FungibleBucket,
+            receipt: NonFungibleBucket,
         ) -> FungibleBucket {
+            assert!(
+                receipt.resource_address() == self.flash_mint_manager.address(),
+                "Not a flash mint receipt"
+            );
+            assert!(fusd.resource_address() == self.fusd_address, "Not fUSD");
+
+            let receipt_data: FlashMintReceipt = receipt.non_fungible().data();
+            let to_burn = receipt_data.amount + receipt_data.fee;
+            assert!(
+                fusd.amount() >= to_burn,
+                "Not enough fUSD to repay flash mint"
+            );
+
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::BurnFusd, &self.badge_vault);
+            self.badge_vault.authorize_with_amount(badge_amount, || {
+                fusd.take(to_burn).burn();
+            });
+
+            receipt.burn();
+            self.flash_mint_outstanding -= receipt_data.amount;
+            self.stats.flash_mint_fees += receipt_data.fee;
+
+            fusd
+        }
+
+        // None switches back to a zero spread
+        pub fn set_peg_controller(
+            &mut self,
+            token: ResourceAddress,
+            price_feed: Option<ComponentAddress>,
+            price_method: String,
+            sensitivity: Decimal,
+            max_spread: Decimal,
+            max_change_per_period: Decimal,
+            period_seconds: i64,
+        ) -> u64 {
+            assert!(self.usd_tokens.get(&token).is_some(), "Unknown token");
+            let peg_controller = price_feed.map(|price_feed| {
+                assert!(
+                    sensitivity >= Decimal::ZERO,
+                    "Sensitivity cannot be negative"
+                );
+                assert!(
+                    max_spread >= Decimal::ZERO && max_spread <= MAX_PEG_SPREAD,
+                    "Max spread must be between 0 and MAX_PEG_SPREAD"
+                );
+                assert!(
+                    max_change_per_period >= Decimal::ZERO && max_change_per_period <= max_spread,
+                    "Max change must be between 0 and the max spread"
+                );
+                assert!(period_seconds > 0, "Period must be positive");
+
+                PegController {
+                    price_feed,
+                    price_method,
+                    sensitivity,
+                    max_spread,
+                    max_change_per_period,
+                    period_seconds,
+                    spread: Decimal::ZERO,
+                    last_update: Clock::current_time_rounded_to_seconds(),
+                }
+            });
+
+            self.queue_action(PendingAction::SetPegController {
+                token,
+                peg_controller,
+            })
+        }
+
+        // anyone can push the latest market price into the token's spread
+        pub fn update_peg_spread(&mut self, token: ResourceAddress) {
+            self.assert_not_migrated();
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let peg_controller = usd_token_state
+                .latest_mut()
+                .peg_controller
+                .as_mut()
+                .expect("No peg controller set");
+            let price_feed: Global<AnyComponent> = Global::from(peg_controller.price_feed);
+            let fusd_price: Decimal =
+                price_feed.call_raw(&peg_controller.price_method, scrypto_args!());
+
+            peg_controller.update(fusd_price);
+        }
+
+        // returns the current mint and redeem spread of the token
+        pub fn get_peg_spreads(&self, token: ResourceAddress) -> (Decimal, Decimal) {
             self.usd_tokens
-                .get_mut(&usd_token)
-                .unwrap()
-                .vault
-                .take(amount)
+                .upgraded(&token)
+                .latest()
+                .peg_controller
+                .as_ref()
+                .map(|peg_controller| {
+                    (peg_controller.mint_spread(), peg_controller.redeem_spread())
+                })
+                .unwrap_or((Decimal::ZERO, Decimal::ZERO))
+        }
+
+        pub fn set_flash_mint_fee(&mut self, fee: Decimal) {
+            self.assert_not_migrated();
+            assert!(fee >= Decimal::ZERO, "Fee cannot be negative");
+            self.flash_mint_fee = fee;
+        }
+
+        pub fn set_flash_mint_cap(&mut self, cap: Decimal) {
+            self.assert_not_migrated();
+            assert!(cap >= Decimal::ZERO, "Cap cannot be negative");
+            self.flash_mint_cap = cap;
+        }
+
+        pub fn receive_badges(&mut self, badge_bucket: Bucket) {
+            self.assert_not_migrated();
+            self.badge_vault.put(badge_bucket.as_fungible());
+        }
+
+        pub fn set_oracle(
+            &mut self,
+            oracle_address: ComponentAddress,
+            single_method_name: String,
+        ) -> u64 {
+            self.queue_action(PendingAction::SetOracle {
+                oracle_address,
+                single_method_name,
+            })
+        }
+
+        // None removes the strategy, only possible once everything is withdrawn from it
+        pub fn set_strategy(
+            &mut self,
+            token: ResourceAddress,
+            strategy: Option<StrategyConfig>,
+        ) -> u64 {
+            assert!(self.usd_tokens.get(&token).is_some(), "Token not found");
+            if let Some(strategy) = &strategy {
+                assert!(
+                    strategy.allocation_cap >= Decimal::ZERO
+                        && strategy.min_liquid_buffer >= Decimal::ZERO,
+                    "Cap and buffer must be positive"
+                );
+            }
+            self.queue_action(PendingAction::SetStrategy { token, strategy })
+        }
+
+        pub fn deploy_to_strategy(&mut self, token: ResourceAddress, amount: Decimal) {
+            self.assert_not_migrated();
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
+            let strategy = usd_token.strategy.clone().expect("No strategy set");
+
+            assert!(
+                usd_token.deployed + amount <= strategy.allocation_cap,
+                "Amount above strategy allocation cap"
+            );
+            assert!(
+                usd_token.vault.amount() - amount >= strategy.min_liquid_buffer,
+                "Not enough liquid reserves left after deploying"
+            );
+
+            let usd: Bucket = usd_token.vault.take(amount).into();
+            usd_token.deployed += amount;
+
+            let strategy_component: Global<AnyComponent> = Global::from(strategy.component);
+            strategy_component.call_raw::<()>(&strategy.deposit_method, scrypto_args!(usd));
+        }
+
+        pub fn withdraw_from_strategy(&mut self, token: ResourceAddress, amount: Decimal) {
+            self.assert_not_migrated();
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
+            let strategy = usd_token.strategy.clone().expect("No strategy set");
+            assert!(
+                amount <= usd_token.deployed,
+                "Amount above deployed reserves, use harvest_strategy for profits"
+            );
+
+            let strategy_component: Global<AnyComponent> = Global::from(strategy.component);
+            let usd: Bucket =
+                strategy_component.call_raw(&strategy.withdraw_method, scrypto_args!(amount));
+            assert!(
+                usd.resource_address() == token && usd.amount() == amount,
+                "Strategy returned the wrong amount"
+            );
+
+            usd_token.deployed -= amount;
+            usd_token.vault.put(usd.as_fungible());
+        }
+
+        // profits go to the treasury, losses lower the deployed amount and with it the backing
+        pub fn harvest_strategy(&mut self, token: ResourceAddress) {
+            self.assert_not_migrated();
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
+            let strategy = usd_token.strategy.clone().expect("No strategy set");
+
+            let strategy_component: Global<AnyComponent> = Global::from(strategy.component);
+            let balance: Decimal =
+                strategy_component.call_raw(&strategy.balance_method, scrypto_args!());
+
+            if balance <= usd_token.deployed {
+                usd_token.deployed = balance;
+                return;
+            }
+
+            let profit = balance - usd_token.deployed;
+            let usd: Bucket =
+                strategy_component.call_raw(&strategy.withdraw_method, scrypto_args!(profit));
+            assert!(
+                usd.resource_address() == token,
+                "Strategy returned the wrong token"
+            );
+            drop(usd_token_state);
+
+            self.send_to_treasury(usd);
+        }
+
+        // oracle keys are rotated by switching to the method that verifies against the new key set
+        // only methods allowed by the owner through the timelock can be used
+        pub fn rotate_oracle_method(&mut self, single_method_name: String) {
+            self.assert_not_migrated();
+            assert!(
+                self.oracle_methods.contains(&single_method_name),
+                "Oracle method not allowed"
+            );
+            self.oracle_method_name = single_method_name;
+        }
+
+        // the current method stays in use until rotated, even if it isn't in the new list
+        pub fn set_oracle_methods(&mut self, method_names: Vec<String>) -> u64 {
+            self.queue_action(PendingAction::SetOracleMethods { method_names })
+        }
+
+        pub fn get_oracle_methods(&self) -> Vec<String> {
+            self.oracle_methods.clone()
+        }
+
+        pub fn send_badges(&mut self, amount: Decimal, receiver_address: ComponentAddress) -> u64 {
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            self.queue_action(PendingAction::SendBadges {
+                amount,
+                receiver_address,
+            })
+        }
+
+        // pass None to go back to presenting the full badge balance for this operation
+        pub fn set_badge_amount(&mut self, operation: BadgeOperation, amount: Option<Decimal>) {
+            self.assert_not_migrated();
+            match amount {
+                Some(amount) => {
+                    assert!(amount > Decimal::ZERO, "Badge amount must be positive");
+                    self.badge_amounts.amounts.insert(operation, amount);
+                }
+                None => {
+                    self.badge_amounts.amounts.swap_remove(&operation);
+                }
+            }
+        }
+
+        pub fn get_badge_amounts(&self) -> IndexMap<BadgeOperation, Decimal> {
+            self.badge_amounts.amounts.clone()
+        }
+
+        // the usd is sent to the treasury once the action is executed
+        pub fn take_usd_from_vault(&mut self, usd_token: ResourceAddress, amount: Decimal) -> u64 {
+            assert!(amount > Decimal::ZERO, "Amount must be positive");
+            assert!(self.usd_tokens.get(&usd_token).is_some(), "Unknown token");
+            self.queue_action(PendingAction::TakeUsdFromVault { usd_token, amount })
         }
 
         // this is public, if you want to put money in a vault you cannot take out, feel free to
+        // donations are counted as surplus
         pub fn put_usd_in_vault(&mut self, usd_token: ResourceAddress, usd_bucket: FungibleBucket) {
-            self.usd_tokens
-                .get_mut(&usd_token)
-                .unwrap()
-                .vault
-                .put(usd_bucket);
+            self.assert_not_migrated();
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&usd_token);
+            let usd_token = usd_token_state.latest_mut();
+            usd_token.surplus += usd_bucket.amount();
+            usd_token.vault.put(usd_bucket);
         }
 
-        pub fn retrieve_collateral(&mut self, cdp_proof: NonFungibleProof) -> FungibleBucket {
-            let cdp_proof = cdp_proof.check_with_message(
-                self.cdp_address,
-                "Incorrect proof! Are you sure this loan is yours?",
+        // returns None if there is no collateral parked for this CDP
+        pub fn retrieve_collateral(
+            &mut self,
+            cdp_proof: NonFungibleProof,
+        ) -> Option<FungibleBucket> {
+            self.assert_not_paused(PAUSE_RETRIEVE_COLLATERAL);
+            let cdp_id = self.check_cdp_proof(cdp_proof);
+
+            self.take_retrievable_collateral(&cdp_id)
+        }
+
+        // a single proof can contain multiple CDPs, CDPs without parked collateral are skipped
+        pub fn retrieve_collateral_many(
+            &mut self,
+            cdp_proofs: Vec<NonFungibleProof>,
+        ) -> Vec<FungibleBucket> {
+            self.assert_not_paused(PAUSE_RETRIEVE_COLLATERAL);
+            let mut collateral: Vec<FungibleBucket> = vec![];
+
+            for cdp_proof in cdp_proofs {
+                let cdp_proof = cdp_proof.check_with_message(
+                    self.cdp_address,
+                    "Incorrect proof! Are you sure this loan is yours?",
+                );
+
+                for cdp_id in cdp_proof.non_fungible_local_ids() {
+                    if let Some(bucket) = self.take_retrievable_collateral(&cdp_id) {
+                        collateral.push(bucket);
+                    }
+                }
+            }
+
+            collateral
+        }
+
+        // surplus collateral of this CDP is deposited directly into the account, as long as it holds the CDP receipt
+        // pass None to stop forwarding
+        pub fn set_surplus_account(
+            &mut self,
+            cdp_proof: NonFungibleProof,
+            account_address: Option<ComponentAddress>,
+        ) {
+            let cdp_id = self.check_cdp_proof(cdp_proof);
+
+            match account_address {
+                Some(account_address) => {
+                    assert!(
+                        account_address.as_node_id().is_global_account(),
+                        "Surplus can only be forwarded to an account"
+                    );
+                    self.surplus_accounts
+                        .insert(cdp_id, Global::from(account_address));
+                }
+                None => {
+                    self.surplus_accounts.remove(&cdp_id);
+                }
+            }
+        }
+
+        pub fn get_surplus_account(&self, cdp_id: NonFungibleLocalId) -> Option<ComponentAddress> {
+            self.surplus_accounts
+                .get(&cdp_id)
+                .map(|account| account.address())
+        }
+
+        // turns collateral parked for this CDP into a transferable claim NFT, so it can be redeemed without the CDP receipt
+        pub fn claim_retrievable_collateral(&mut self, cdp_proof: NonFungibleProof) -> Bucket {
+            self.assert_not_paused(PAUSE_RETRIEVE_COLLATERAL);
+            let cdp_id = self.check_cdp_proof(cdp_proof);
+
+            let collateral = self
+                .take_retrievable_collateral(&cdp_id)
+                .expect("No collateral to retrieve for this CDP");
+
+            self.mint_collateral_claim(cdp_id, collateral)
+        }
+
+        pub fn redeem_collateral_claims(
+            &mut self,
+            claims: NonFungibleBucket,
+        ) -> Vec<FungibleBucket> {
+            self.assert_not_paused(PAUSE_RETRIEVE_COLLATERAL);
+            assert!(
+                claims.resource_address() == self.claim_manager.address(),
+                "Not a collateral claim"
             );
 
-            let cdp = cdp_proof.non_fungible::<Cdp>();
-            let cdp_id: NonFungibleLocalId = cdp.local_id().clone();
+            let collateral: Vec<FungibleBucket> = claims
+                .non_fungible_local_ids()
+                .iter()
+                .map(|claim_id| {
+                    self.claimable_collateral
+                        .get_mut(claim_id)
+                        .unwrap()
+                        .take_all()
+                })
+                .collect();
 
-            self.retrievable_collateral
-                .get_mut(&cdp_id)
-                .unwrap()
-                .take_all()
+            claims.burn();
+
+            collateral
         }
 
         // admin should be able to recover collateral, for if the user burns their receipt before redeeming here, accidentally
-        // this is not that much of an issue, since the admin will be the DAO. the DAO already has access to all funds and is obviously decentralized.
-        pub fn retrieve_collateral_admin(&mut self, cdp_id: NonFungibleLocalId) -> FungibleBucket {
-            self.retrievable_collateral
-                .get_mut(&cdp_id)
-                .unwrap()
-                .take_all()
+        // the CDP holder gets the grace period to retrieve it first, after that it can only be swept to the treasury.
+        pub fn retrieve_collateral_admin(&mut self, cdp_id: NonFungibleLocalId) -> u64 {
+            assert!(
+                self.retrievable_collateral.get(&cdp_id).is_some(),
+                "No collateral to retrieve for this CDP"
+            );
+            self.queue_action(PendingAction::RetrieveCollateralAdmin { cdp_id })
+        }
+
+        // the delay of SetActionDelay itself applies, so delays cannot be shortened without notice
+        pub fn set_action_delay(&mut self, action_type: ActionType, delay: i64) -> u64 {
+            assert!(delay >= action_type.min_delay(), "Delay too short");
+            self.queue_action(PendingAction::SetActionDelay { action_type, delay })
+        }
+
+        // moves all PSM vaults, their accounting and the badges to a successor implementing receive_migration
+        // afterwards this component is permanently disabled, except for retrieving parked collateral
+        pub fn migrate(&mut self, successor_address: ComponentAddress) -> u64 {
+            self.queue_action(PendingAction::Migrate { successor_address })
         }
 
-        pub fn get_usd_amount_in_vault(&mut self, usd_token: ResourceAddress) -> Decimal {
-            self.usd_tokens.get_mut(&usd_token).unwrap().vault.amount()
+        pub fn get_migrated_to(&self) -> Option<ComponentAddress> {
+            self.migrated_to
+        }
+
+        pub fn execute_action(&mut self, action_id: u64) {
+            let queued = self
+                .pending_actions
+                .remove(&action_id)
+                .expect("No pending action with this id");
+            assert!(
+                Clock::current_time_is_at_or_after(queued.executable_at, TimePrecision::Second),
+                "Action is still timelocked"
+            );
+            if !matches!(queued.action, PendingAction::RetrieveCollateralAdmin { .. }) {
+                self.assert_not_migrated();
+            }
+
+            match queued.action.clone() {
+                PendingAction::SetOracle {
+                    oracle_address,
+                    single_method_name,
+                } => {
+                    // methods allowed on the old oracle don't carry over
+                    self.oracle = Global::from(oracle_address);
+                    self.oracle_methods = vec![single_method_name.clone()];
+                    self.oracle_method_name = single_method_name;
+                }
+                PendingAction::SetFine { fine } => {
+                    self.fine = fine;
+                }
+                PendingAction::SetUsdPerFusd {
+                    token,
+                    usd_per_fusd,
+                } => {
+                    // bounds may have changed since the action was queued
+                    self.assert_usd_per_fusd_in_bounds(usd_per_fusd);
+                    self.usd_tokens
+                        .upgraded_mut(&token)
+                        .latest_mut()
+                        .usd_per_fusd = usd_per_fusd;
+                }
+                PendingAction::TakeUsdFromVault { usd_token, amount } => {
+                    let mut usd_token_state = self.usd_tokens.upgraded_mut(&usd_token);
+                    let usd_token = usd_token_state.latest_mut();
+                    // taken out of the surplus first
+                    usd_token.surplus = (usd_token.surplus - amount).max(Decimal::ZERO);
+                    let usd = usd_token.vault.take(amount);
+                    drop(usd_token_state);
+
+                    self.send_to_treasury(usd.into());
+                }
+                PendingAction::SendBadges {
+                    amount,
+                    receiver_address,
+                } => {
+                    let receiver: Global<AnyComponent> = Global::from(receiver_address);
+                    let badge_bucket: Bucket = self.badge_vault.take(amount).into();
+                    receiver.call_raw::<()>("receive_badges", scrypto_args!(badge_bucket));
+                }
+                PendingAction::RetrieveCollateralAdmin { cdp_id } => {
+                    self.sweep_expired_collateral(cdp_id);
+                }
+                PendingAction::SetActionDelay { action_type, delay } => {
+                    self.action_delays.insert(action_type, delay);
+                }
+                PendingAction::SetUsdPerFusdBounds { min, max } => {
+                    self.usd_per_fusd_min = min;
+                    self.usd_per_fusd_max = max;
+                }
+                PendingAction::SetUsdPerFusdMaxChange { max_change } => {
+                    self.usd_per_fusd_tune_limit =
+                        RateLimit::new(max_change, USD_PER_FUSD_TUNE_WINDOW);
+                }
+                PendingAction::SetTreasury {
+                    treasury_address,
+                    single_method_name,
+                } => {
+                    self.treasury = Some(Global::from(treasury_address));
+                    self.treasury_method_name = single_method_name;
+                }
+                PendingAction::SetCollateralGracePeriod { grace_period } => {
+                    self.collateral_grace_period = grace_period;
+                }
+                PendingAction::SetOracleMethods { method_names } => {
+                    self.oracle_methods = method_names;
+                }
+                PendingAction::SetRedemptionDiscount {
+                    redemption_discount,
+                } => {
+                    self.redemption_discount = redemption_discount;
+                }
+                PendingAction::SetPegController {
+                    token,
+                    peg_controller,
+                } => {
+                    // the spread starts at zero and only moves from the moment the controller is active
+                    self.usd_tokens
+                        .upgraded_mut(&token)
+                        .latest_mut()
+                        .peg_controller = peg_controller.map(|peg_controller| PegController {
+                        spread: Decimal::ZERO,
+                        last_update: Clock::current_time_rounded_to_seconds(),
+                        ..peg_controller
+                    });
+                }
+                PendingAction::Migrate { successor_address } => {
+                    self.migrate_to(successor_address);
+                }
+                PendingAction::SetStrategy { token, strategy } => {
+                    let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+                    let usd_token = usd_token_state.latest_mut();
+                    let same_component = match (&usd_token.strategy, &strategy) {
+                        (Some(current), Some(new)) => current.component == new.component,
+                        _ => false,
+                    };
+                    assert!(
+                        usd_token.deployed == Decimal::ZERO || same_component,
+                        "Withdraw from the current strategy first"
+                    );
+                    usd_token.strategy = strategy;
+                }
+            }
+
+            Runtime::emit_event(ActionExecutedEvent {
+                action_id,
+                action: queued.action,
+            });
+        }
+
+        pub fn cancel_action(&mut self, action_id: u64) {
+            let queued = self
+                .pending_actions
+                .remove(&action_id)
+                .expect("No pending action with this id");
+
+            Runtime::emit_event(ActionCancelledEvent {
+                action_id,
+                action: queued.action,
+            });
+        }
+
+        pub fn get_pending_action(&self, action_id: u64) -> Option<QueuedAction> {
+            self.pending_actions
+                .get(&action_id)
+                .map(|queued| queued.clone())
+        }
+
+        pub fn get_action_delays(&self) -> IndexMap<ActionType, i64> {
+            self.action_delays.clone()
+        }
+
+        // only applies to collateral parked after the change is executed
+        pub fn set_collateral_grace_period(&mut self, grace_period: i64) -> u64 {
+            assert!(
+                grace_period >= MIN_COLLATERAL_GRACE_PERIOD,
+                "Grace period too short"
+            );
+            self.queue_action(PendingAction::SetCollateralGracePeriod { grace_period })
+        }
+
+        pub fn set_treasury(
+            &mut self,
+            treasury_address: ComponentAddress,
+            single_method_name: String,
+        ) -> u64 {
+            self.queue_action(PendingAction::SetTreasury {
+                treasury_address,
+                single_method_name,
+            })
+        }
+
+        // CDPs without parked collateral are left out
+        pub fn get_retrievable_collateral(
+            &self,
+            cdp_ids: Vec<NonFungibleLocalId>,
+        ) -> Vec<RetrievableCollateralInfo> {
+            cdp_ids
+                .into_iter()
+                .filter_map(|cdp_id| {
+                    let parked = self.retrievable_collateral.get(&cdp_id)?;
+                    let amount = parked.vault.amount();
+                    (amount > Decimal::ZERO).then(|| RetrievableCollateralInfo {
+                        collateral_address: parked.vault.resource_address(),
+                        claimable_until: parked.claimable_until,
+                        cdp_id,
+                        amount,
+                    })
+                })
+                .collect()
+        }
+
+        pub fn get_surplus(&self, usd_token: ResourceAddress) -> Decimal {
+            self.usd_tokens.upgraded(&usd_token).latest().surplus
+        }
+
+        pub fn get_usd_amount_in_vault(&self, usd_token: ResourceAddress) -> Decimal {
+            self.usd_tokens.upgraded(&usd_token).latest().vault.amount()
+        }
+
+        // returns None if no limit is set for this token
+        pub fn get_remaining_mint_capacity(&self, usd_token: ResourceAddress) -> Option<Decimal> {
+            self.usd_tokens
+                .upgraded(&usd_token)
+                .latest()
+                .mint_limit
+                .as_ref()
+                .map(|mint_limit| mint_limit.remaining())
+        }
+
+        pub fn get_remaining_redeem_capacity(&self, usd_token: ResourceAddress) -> Option<Decimal> {
+            self.usd_tokens
+                .upgraded(&usd_token)
+                .latest()
+                .redeem_limit
+                .as_ref()
+                .map(|redeem_limit| redeem_limit.remaining())
+        }
+
+        pub fn get_stats(&self) -> Stats {
+            self.stats.clone()
+        }
+
+        // returns the strategy and the amount deployed in it
+        pub fn get_strategy(&self, token: ResourceAddress) -> Option<(StrategyConfig, Decimal)> {
+            let usd_token_state = self.usd_tokens.upgraded(&token);
+            let usd_token = usd_token_state.latest();
+            usd_token
+                .strategy
+                .clone()
+                .map(|strategy| (strategy, usd_token.deployed))
+        }
+
+        pub fn get_psm_access(&self, token: ResourceAddress) -> PsmAccess {
+            self.usd_tokens.upgraded(&token).latest().access.clone()
+        }
+
+        pub fn get_stability_pool_units(&self, collateral: ResourceAddress) -> Decimal {
+            self.stability_pool_units
+                .get(&collateral)
+                .map(|position| position.pool_units.amount())
+                .unwrap_or(Decimal::ZERO)
+        }
+
+        // cdps whose collateral has no price in price_per_collateral are left out
+        pub fn find_liquidatable(
+            &self,
+            cdp_ids: Vec<NonFungibleLocalId>,
+            price_per_collateral: IndexMap<ResourceAddress, Decimal>,
+        ) -> Vec<LiquidationCandidate> {
+            let cdp_manager: NonFungibleResourceManager =
+                NonFungibleResourceManager::from(self.cdp_address);
+            let stability_pool_infos = STABILITY_POOLS
+                .get_stability_pool_infos(Some(price_per_collateral.keys().cloned().collect()));
+
+            cdp_ids
+                .into_iter()
+                .filter_map(|cdp_id| {
+                    let receipt_data: Cdp = cdp_manager.get_non_fungible_data(&cdp_id);
+                    let collateral_price =
+                        *price_per_collateral.get(&receipt_data.collateral_address)?;
+                    let fusd_in_stability_pool: Decimal = stability_pool_infos
+                        .iter()
+                        .find(|info| info.collateral == receipt_data.collateral_address)
+                        .map(|info| info.fusd_amount)
+                        .unwrap_or(Decimal::ZERO);
+
+                    let (liquidatable, real_debt, collateral_address) =
+                        FLUX.check_liquidate_cdp(cdp_id.clone(), Some(collateral_price));
+
+                    // same minimum of 1 dollar of collateral left in the cdp as partial_liquidate_cdp
+                    let max_profitable_repay =
+                        ((receipt_data.collateral_amount * collateral_price - Decimal::ONE)
+                            / self.fine)
+                            .min(real_debt)
+                            .max(Decimal::ZERO);
+                    let max_profitable_repay =
+                        if liquidatable && max_profitable_repay > fusd_in_stability_pool {
+                            max_profitable_repay
+                        } else {
+                            Decimal::ZERO
+                        };
+
+                    Some(LiquidationCandidate {
+                        cdp_id,
+                        collateral_address,
+                        liquidatable,
+                        real_debt,
+                        fusd_in_stability_pool,
+                        max_profitable_repay,
+                    })
+                })
+                .collect()
+        }
+
+        // cdps without recorded bad debt are left out
+        pub fn get_bad_debts(
+            &self,
+            cdp_ids: Vec<NonFungibleLocalId>,
+        ) -> Vec<(NonFungibleLocalId, Decimal)> {
+            cdp_ids
+                .into_iter()
+                .filter_map(|cdp_id| {
+                    let bad_debt = *self.bad_debts.get(&cdp_id)?;
+                    Some((cdp_id, bad_debt))
+                })
+                .collect()
+        }
+
+        // liquidators without any recorded liquidations are left out
+        pub fn get_liquidator_stats(
+            &self,
+            liquidators: Vec<LiquidatorId>,
+        ) -> Vec<(LiquidatorId, LiquidatorStats)> {
+            liquidators
+                .into_iter()
+                .filter_map(|liquidator| {
+                    let stats = self.liquidator_stats.get(&liquidator)?.clone();
+                    Some((liquidator, stats))
+                })
+                .collect()
         }
 
         pub fn get_collateral_price(
@@ -492,20 +2668,550 @@ mod flux_addition {
             )
         }
 
+        // closes a CDP with less debt than max_debt, paying out collateral worth fusd_spent * payout_multiplier
+        // the rest of the collateral is surplus for the CDP holder
+        fn force_close_cdp(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            fusd: Bucket,
+            collateral_price: Decimal,
+            payout_multiplier: Decimal,
+            max_debt: Decimal,
+            mint_claim: bool,
+        ) -> (Bucket, Option<Bucket>, Option<Bucket>) {
+            let fusd_input_amount: Decimal = fusd.amount();
+            let badge_amount = self
+                .badge_amounts
+                .amount_for(BadgeOperation::CloseCdp, &self.badge_vault);
+
+            let (mut collateral, leftover_fusd): (Bucket, Bucket) = self
+                .badge_vault
+                .authorize_with_amount(badge_amount, || FLUX.close_cdp(cdp_id.clone(), fusd));
+            let fusd_spent: Decimal = fusd_input_amount - leftover_fusd.amount();
+
+            assert!(fusd_spent < max_debt, "Too much debt to forcibly close.");
+
+            let collateral_reward_max = (fusd_spent / collateral_price) * payout_multiplier;
+            let collateral_surplus = collateral.amount() - collateral_reward_max;
+
+            let claim = if collateral_surplus > Decimal::ZERO {
+                let surplus_bucket = collateral.take(collateral_surplus);
+                self.park_surplus_collateral(cdp_id, surplus_bucket.as_fungible(), mint_claim)
+            } else {
+                None
+            };
+
+            self.stats.record_forced_close(
+                collateral.resource_address(),
+                collateral.amount() - fusd_spent / collateral_price,
+            );
+
+            if leftover_fusd.amount() > Decimal::ZERO {
+                (collateral, Some(leftover_fusd), claim)
+            } else {
+                leftover_fusd.drop_empty();
+                (collateral, None, claim)
+            }
+        }
+
+        fn migrate_to(&mut self, successor_address: ComponentAddress) {
+            let mut usd_tokens: Vec<MigratedUsdToken> = vec![];
+
+            for resource_address in self.usd_token_addresses.iter() {
+                let mut usd_token_state = self.usd_tokens.upgraded_mut(resource_address);
+                let usd_token = usd_token_state.latest_mut();
+                assert!(
+                    usd_token.deployed == Decimal::ZERO,
+                    "Withdraw all strategy funds before migrating"
+                );
+                let backing = usd_token.backing();
+                let backing_per_fusd = (usd_token.fusd_minted > Decimal::ZERO)
+                    .then(|| (backing - usd_token.surplus) / usd_token.fusd_minted);
+                let usd = usd_token.vault.take_all();
+
+                usd_tokens.push(MigratedUsdToken {
+                    resource_address: *resource_address,
+                    usd: usd.into(),
+                    backing,
+                    backing_per_fusd,
+                    accepted: usd_token.accepted,
+                    usd_per_fusd: usd_token.usd_per_fusd,
+                    fusd_minted: usd_token.fusd_minted,
+                    mint_limit: usd_token.mint_limit.clone(),
+                    redeem_limit: usd_token.redeem_limit.clone(),
+                    access: usd_token.access.clone(),
+                    fx_priced: usd_token.fx_priced,
+                    surplus: usd_token.surplus,
+                });
+
+                usd_token.accepted = false;
+                usd_token.fusd_minted = Decimal::ZERO;
+                usd_token.surplus = Decimal::ZERO;
+            }
+
+            let badges = self.badge_vault.take_all();
+            let badge_amount = badges.amount();
+
+            // empty vaults stay behind, they can't be dropped
+            let stability_pool_units: Vec<MigratedStabilityPoolUnits> = self
+                .stability_pool_collaterals
+                .iter()
+                .map(|collateral| {
+                    let mut position = self.stability_pool_units.get_mut(collateral).unwrap();
+                    let fusd_contributed = position.fusd_contributed;
+                    position.fusd_contributed = Decimal::ZERO;
+
+                    MigratedStabilityPoolUnits {
+                        collateral: *collateral,
+                        pool_units: position.pool_units.take_all().into(),
+                        funding_token: position.funding_token,
+                        fusd_contributed,
+                    }
+                })
+                .collect();
+            let stability_pool_top_up_total = self.stability_pool_top_up_total;
+            self.stability_pool_top_up_total = Decimal::ZERO;
+
+            let successor: Global<AnyComponent> = Global::from(successor_address);
+            successor.call_raw::<()>(
+                "receive_migration",
+                scrypto_args!(Migration {
+                    usd_tokens,
+                    badges: badges.into(),
+                    badge_amount,
+                    stability_pool_units,
+                    stability_pool_top_up_total,
+                }),
+            );
+
+            self.paused = PAUSE_ALL & !PAUSE_RETRIEVE_COLLATERAL;
+            self.migrated_to = Some(successor_address);
+
+            Runtime::emit_event(MigratedEvent {
+                successor_address,
+                usd_tokens: self.usd_token_addresses.clone(),
+                badge_amount,
+            });
+        }
+
+        fn assert_not_migrated(&self) {
+            assert!(
+                self.migrated_to.is_none(),
+                "This component has been migrated"
+            );
+        }
+
+        // only surplus can be used, so redemptions stay fully backed
+        fn take_stable_bonus(
+            &mut self,
+            token: ResourceAddress,
+            fusd_value: Decimal,
+        ) -> FungibleBucket {
+            let mut usd_token_state = self.usd_tokens.upgraded_mut(&token);
+            let usd_token = usd_token_state.latest_mut();
+            // disabled tokens and tokens restricted to certain holders are not paid out to just anyone
+            assert!(usd_token.accepted, "Token not accepted");
+            usd_token.access.assert_access();
+
+            let bonus_amount = fusd_value * usd_token.usd_per_fusd;
+            usd_token.spend_surplus(bonus_amount);
+
+            usd_token.vault.take(bonus_amount)
+        }
+
+        // bad debt is the part of the debt not covered by the collateral's value
+        fn update_bad_debt(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            collateral_price: Decimal,
+        ) -> Decimal {
+            let cdp_manager: NonFungibleResourceManager =
+                NonFungibleResourceManager::from(self.cdp_address);
+            let receipt_data: Cdp = cdp_manager.get_non_fungible_data(&cdp_id);
+            let (_liquidatable, real_debt, _collateral_address) =
+                FLUX.check_liquidate_cdp(cdp_id.clone(), Some(collateral_price));
+
+            let bad_debt =
+                (real_debt - receipt_data.collateral_amount * collateral_price).max(Decimal::ZERO);
+            if bad_debt > Decimal::ZERO {
+                self.bad_debts.insert(cdp_id, bad_debt);
+            } else {
+                self.bad_debts.remove(&cdp_id);
+            }
+
+            bad_debt
+        }
+
+        fn liquidator_id(&self, liquidator: LiquidatorIdentity) -> LiquidatorId {
+            match liquidator {
+                LiquidatorIdentity::Badge(badge_proof) => {
+                    let badge_proof = badge_proof.skip_checking();
+                    let local_ids = badge_proof.non_fungible_local_ids();
+                    assert!(
+                        local_ids.len() == 1,
+                        "Liquidator badge proof must contain exactly one non-fungible"
+                    );
+                    LiquidatorId::Badge(NonFungibleGlobalId::new(
+                        badge_proof.resource_address(),
+                        local_ids.into_iter().next().unwrap(),
+                    ))
+                }
+                LiquidatorIdentity::Account(account_address) => {
+                    LiquidatorId::Account(account_address)
+                }
+            }
+        }
+
+        fn record_liquidation(
+            &mut self,
+            liquidator_id: LiquidatorId,
+            fusd_repaid: Decimal,
+            earned: &[&Bucket],
+        ) {
+            let mut stats = self
+                .liquidator_stats
+                .get(&liquidator_id)
+                .map(|stats| stats.clone())
+                .unwrap_or_default();
+
+            stats.liquidations += 1;
+            stats.fusd_repaid += fusd_repaid;
+            for bucket in earned {
+                *stats
+                    .earned
+                    .entry(bucket.resource_address())
+                    .or_insert(Decimal::ZERO) += bucket.amount();
+            }
+
+            self.liquidator_stats.insert(liquidator_id, stats);
+        }
+
+        // USD price of an FX priced token from the collateral oracle, 1 for USD stables
+        fn fx_price(
+            &self,
+            token: ResourceAddress,
+            message: Option<String>,
+            signature: Option<String>,
+        ) -> Decimal {
+            let fx_priced = self.usd_tokens.upgraded(&token).latest().fx_priced;
+            if !fx_priced {
+                return Decimal::ONE;
+            }
+
+            let fx_price: Decimal = self.oracle.call_raw(
+                &self.oracle_method_name,
+                scrypto_args!(
+                    token,
+                    message.expect("FX priced token needs an oracle message"),
+                    signature.expect("FX priced token needs an oracle signature")
+                ),
+            );
+            assert!(fx_price > Decimal::ZERO, "Invalid FX price");
+
+            fx_price
+        }
+
+        fn assert_usd_per_fusd_in_bounds(&self, usd_per_fusd: Decimal) {
+            assert!(
+                usd_per_fusd >= self.usd_per_fusd_min && usd_per_fusd <= self.usd_per_fusd_max,
+                "usd_per_fusd out of bounds"
+            );
+        }
+
+        fn is_paused(&self, flag: u8) -> bool {
+            self.paused & flag != 0
+        }
+
+        fn assert_not_paused(&self, flag: u8) {
+            assert!(!self.is_paused(flag), "This method is paused");
+        }
+
+        fn check_cdp_proof(&self, cdp_proof: NonFungibleProof) -> NonFungibleLocalId {
+            let cdp_proof = cdp_proof.check_with_message(
+                self.cdp_address,
+                "Incorrect proof! Are you sure this loan is yours?",
+            );
+
+            cdp_proof.non_fungible_local_id()
+        }
+
+        fn check_optional_cdp_proof(
+            &self,
+            cdp_proof: Option<NonFungibleProof>,
+            cdp_id: &NonFungibleLocalId,
+        ) -> bool {
+            match cdp_proof {
+                Some(cdp_proof) => {
+                    assert!(
+                        self.check_cdp_proof(cdp_proof) == *cdp_id,
+                        "Proof does not match the CDP"
+                    );
+                    true
+                }
+                None => false,
+            }
+        }
+
+        // only the CDP holder may receive a claim NFT, otherwise the surplus is forwarded or parked for them
+        fn park_surplus_collateral(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            bucket: FungibleBucket,
+            mint_claim: bool,
+        ) -> Option<Bucket> {
+            if mint_claim {
+                return Some(self.mint_collateral_claim(cdp_id, bucket));
+            }
+
+            if let Some(refunded) = self.forward_surplus_collateral(&cdp_id, bucket) {
+                self.put_retrievable_collateral(cdp_id, refunded);
+            }
+
+            None
+        }
+
+        // returns the bucket if there is no account to forward to, or if the account refuses the deposit
+        fn forward_surplus_collateral(
+            &self,
+            cdp_id: &NonFungibleLocalId,
+            bucket: FungibleBucket,
+        ) -> Option<FungibleBucket> {
+            let mut account: Global<Account> = match self.surplus_accounts.get(cdp_id) {
+                Some(account) => *account,
+                None => return Some(bucket),
+            };
+
+            // the receipt might have changed hands since the account was registered
+            if !account.has_non_fungible(self.cdp_address, cdp_id.clone()) {
+                return Some(bucket);
+            }
+
+            account
+                .try_deposit_or_refund(bucket.into(), None)
+                .map(|refunded| refunded.as_fungible())
+        }
+
+        fn mint_collateral_claim(
+            &mut self,
+            cdp_id: NonFungibleLocalId,
+            collateral: FungibleBucket,
+        ) -> Bucket {
+            let claim = self.claim_manager.mint_ruid_non_fungible(CollateralClaim {
+                cdp_id,
+                collateral_address: collateral.resource_address(),
+                amount: collateral.amount(),
+            });
+
+            self.claimable_collateral.insert(
+                claim.non_fungible_local_id(),
+                FungibleVault::with_bucket(collateral),
+            );
+
+            claim.into()
+        }
+
+        // vaults cannot be dropped, so emptied vaults stay in place and are refilled by put_retrievable_collateral
+        fn take_retrievable_collateral(
+            &mut self,
+            cdp_id: &NonFungibleLocalId,
+        ) -> Option<FungibleBucket> {
+            let mut parked = self.retrievable_collateral.get_mut(cdp_id)?;
+            if parked.vault.amount() == Decimal::ZERO {
+                return None;
+            }
+
+            Some(parked.vault.take_all())
+        }
+
         fn put_retrievable_collateral(
             &mut self,
             cdp_id: NonFungibleLocalId,
             bucket: FungibleBucket,
         ) {
+            let now = Clock::current_time_rounded_to_seconds();
+            let claimable_until = now.add_seconds(self.collateral_grace_period).unwrap();
+
             if self.retrievable_collateral.get(&cdp_id).is_some() {
-                self.retrievable_collateral
-                    .get_mut(&cdp_id)
-                    .unwrap()
-                    .put(bucket);
+                let mut parked = self.retrievable_collateral.get_mut(&cdp_id).unwrap();
+                parked.vault.put(bucket);
+                parked.parked_at = now;
+                // collateral parked earlier keeps at least its original deadline
+                if claimable_until.compare(parked.claimable_until, TimeComparisonOperator::Gt) {
+                    parked.claimable_until = claimable_until;
+                }
             } else {
-                self.retrievable_collateral
-                    .insert(cdp_id, FungibleVault::with_bucket(bucket));
+                self.retrievable_collateral.insert(
+                    cdp_id,
+                    ParkedCollateral {
+                        vault: FungibleVault::with_bucket(bucket),
+                        parked_at: now,
+                        claimable_until,
+                    },
+                );
+            }
+        }
+
+        fn queue_action(&mut self, action: PendingAction) -> u64 {
+            // unclaimed collateral stays here after a migration, so it must remain sweepable
+            if !matches!(action, PendingAction::RetrieveCollateralAdmin { .. }) {
+                self.assert_not_migrated();
             }
+
+            let action_id = self.next_action_id;
+            self.next_action_id += 1;
+
+            let delay = *self.action_delays.get(&action.action_type()).unwrap();
+            let executable_at = Clock::current_time_rounded_to_seconds()
+                .add_seconds(delay)
+                .unwrap();
+
+            self.pending_actions.insert(
+                action_id,
+                QueuedAction {
+                    action: action.clone(),
+                    executable_at,
+                },
+            );
+
+            Runtime::emit_event(ActionQueuedEvent {
+                action_id,
+                action,
+                executable_at,
+            });
+
+            action_id
+        }
+
+        // not while holders can't retrieve, otherwise pausing retrieval would cut their grace period short
+        fn sweep_expired_collateral(&mut self, cdp_id: NonFungibleLocalId) {
+            self.assert_not_paused(PAUSE_RETRIEVE_COLLATERAL);
+            let claimable_until = self
+                .retrievable_collateral
+                .get(&cdp_id)
+                .map(|parked| parked.claimable_until)
+                .expect("No collateral to retrieve for this CDP");
+            assert!(
+                Clock::current_time_is_strictly_after(claimable_until, TimePrecision::Second),
+                "Collateral is still claimable by the CDP holder"
+            );
+
+            let collateral = self
+                .take_retrievable_collateral(&cdp_id)
+                .expect("No collateral to retrieve for this CDP");
+
+            Runtime::emit_event(CollateralSweptEvent {
+                cdp_id,
+                collateral_address: collateral.resource_address(),
+                amount: collateral.amount(),
+            });
+
+            self.send_to_treasury(collateral.into());
+        }
+
+        fn send_to_treasury(&self, bucket: Bucket) {
+            let treasury = self.treasury.expect("No treasury set");
+            treasury.call_raw::<()>(&self.treasury_method_name, scrypto_args!(bucket));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(max_amount: Decimal, window_seconds: i64, used: Decimal) -> RateLimit {
+        RateLimit {
+            max_amount,
+            window_seconds,
+            used,
+            last_update: Instant::new(1_000),
+        }
+    }
+
+    #[test]
+    fn rate_limit_keeps_usage_within_the_same_second() {
+        let limit = rate_limit(dec!("100"), 100, dec!("50"));
+
+        assert_eq!(limit.used_at(Instant::new(1_000)), dec!("50"));
+    }
+
+    #[test]
+    fn rate_limit_releases_usage_linearly() {
+        let limit = rate_limit(dec!("100"), 100, dec!("50"));
+
+        assert_eq!(limit.used_at(Instant::new(1_020)), dec!("30"));
+        assert_eq!(limit.used_at(Instant::new(1_049)), dec!("1"));
+    }
+
+    #[test]
+    fn rate_limit_usage_never_drops_below_zero() {
+        let limit = rate_limit(dec!("100"), 100, dec!("50"));
+
+        assert_eq!(limit.used_at(Instant::new(1_050)), Decimal::ZERO);
+        assert_eq!(limit.used_at(Instant::new(100_000)), Decimal::ZERO);
+    }
+
+    #[test]
+    fn rate_limit_releases_relative_to_max_amount() {
+        let limit = rate_limit(dec!("0.01"), 86_400, dec!("0.01"));
+
+        assert_eq!(limit.used_at(Instant::new(1_000 + 43_200)), dec!("0.005"));
+    }
+
+    fn peg_controller() -> PegController {
+        PegController {
+            price_feed: ComponentAddress::new_or_panic(
+                [EntityType::GlobalGenericComponent as u8; NodeId::LENGTH],
+            ),
+            price_method: "get_price".to_string(),
+            sensitivity: Decimal::ONE,
+            max_spread: dec!("0.05"),
+            max_change_per_period: dec!("0.01"),
+            period_seconds: 100,
+            spread: Decimal::ZERO,
+            last_update: Instant::new(1_000),
         }
     }
+
+    #[test]
+    fn peg_spread_change_is_limited_by_elapsed_time() {
+        let mut controller = peg_controller();
+
+        controller.update_at(dec!("0.9"), Instant::new(1_050));
+
+        assert_eq!(controller.spread, dec!("0.005"));
+        assert_eq!(controller.mint_spread(), dec!("0.005"));
+        assert_eq!(controller.redeem_spread(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn peg_spread_change_is_capped_per_period() {
+        let mut controller = peg_controller();
+
+        controller.update_at(dec!("0.9"), Instant::new(100_000));
+
+        assert_eq!(controller.spread, dec!("0.01"));
+    }
+
+    #[test]
+    fn peg_spread_is_clamped_to_max_spread() {
+        let mut controller = peg_controller();
+
+        for now in (1_100..=2_000).step_by(100) {
+            controller.update_at(dec!("0.5"), Instant::new(now));
+        }
+
+        assert_eq!(controller.spread, dec!("0.05"));
+    }
+
+    #[test]
+    fn peg_spread_turns_into_a_redeem_fee_above_peg() {
+        let mut controller = peg_controller();
+
+        controller.update_at(dec!("1.003"), Instant::new(1_100));
+
+        assert_eq!(controller.spread, dec!("-0.003"));
+        assert_eq!(controller.mint_spread(), Decimal::ZERO);
+        assert_eq!(controller.redeem_spread(), dec!("0.003"));
+    }
 }